@@ -0,0 +1,180 @@
+//! Evaluation of large multivariate flags
+//!
+//! Compares flags shared by the store with copying the
+//! flag data on every access, like the store used to.
+//! The batch group compares evaluating the flags of a request
+//! one by one with evaluating them in a single batch.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use launchdarkly_rust_sdk_alt::{
+    compiled::CompiledFlag,
+    consumer::Consumer,
+    evaluator::{Evaluate, Evaluator, User},
+    message::{InitData, Message},
+    models::FeatureFlagState,
+    source::NoSource,
+    store::{MemoryStore, Store},
+};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
+
+const VARIATIONS: usize = 100;
+const TARGETS_PER_VARIATION: usize = 20;
+const RULES: usize = 50;
+const CLAUSE_VALUES: usize = 20;
+/// Flags evaluated per request in the batch bench
+const BATCH_SIZE: usize = 30;
+
+/// Flag with many variations, targets and rules, none matching the bench user
+fn large_flag(key: &str, prerequisites: &[&str]) -> FeatureFlagState {
+    let variations: Vec<_> = (0..VARIATIONS)
+        .map(
+            |i| json!({ "name": format!("variation-{}", i), "weight": i, "tags": ["a", "b", "c"] }),
+        )
+        .collect();
+    let targets: Vec<_> = (0..VARIATIONS)
+        .map(|i| {
+            let values: Vec<_> = (0..TARGETS_PER_VARIATION)
+                .map(|j| format!("target-{}-{}", i, j))
+                .collect();
+            json!({ "variation": i, "values": values })
+        })
+        .collect();
+    let rules: Vec<_> = (0..RULES)
+        .map(|i| {
+            let values: Vec<_> = (0..CLAUSE_VALUES)
+                .map(|j| format!("rule-{}-{}", i, j))
+                .collect();
+            json!({
+                "id": format!("rule-{}", i),
+                "variation": i % VARIATIONS,
+                "clauses": [{ "attribute": "key", "op": "in", "values": values }],
+            })
+        })
+        .collect();
+    let prerequisites: Vec<_> = prerequisites
+        .iter()
+        .map(|k| json!({ "key": k, "variation": 0 }))
+        .collect();
+    serde_json::from_value(json!({
+        "key": key,
+        "version": 1,
+        "on": true,
+        "deleted": false,
+        "salt": "salt",
+        "clientSide": false,
+        "clientSideAvailability": {},
+        "debugEventsUntilDate": null,
+        "trackEvents": false,
+        "trackEventsFallthrough": false,
+        "offVariation": 0,
+        "fallthrough": { "variation": 0 },
+        "variations": variations,
+        "targets": targets,
+        "rules": rules,
+        "prerequisites": prerequisites,
+    }))
+    .expect("invalid flag")
+}
+
+fn store() -> Arc<MemoryStore> {
+    let mut flags = vec![
+        large_flag("prereq-a", &[]),
+        large_flag("prereq-b", &[]),
+        large_flag("large", &["prereq-a", "prereq-b"]),
+    ];
+    // flags sharing the prerequisites, like the flags of a request
+    flags.extend((0..BATCH_SIZE).map(|i| large_flag(&batch_key(i), &["prereq-a", "prereq-b"])));
+    let flags: HashMap<_, _> = flags.into_iter().map(|f| (f.key.clone(), f)).collect();
+    let store = Arc::new(MemoryStore::new());
+    block_on(<MemoryStore as Consumer<NoSource>>::consume(
+        &store,
+        Message::Put(InitData::with_flags(flags)),
+    ))
+    .expect("failed to init store");
+    store
+}
+
+fn batch_key(i: usize) -> String {
+    format!("batch-{}", i)
+}
+
+/// Copies every flag it hands out
+struct CloningStore(Arc<MemoryStore>);
+
+impl Store for CloningStore {
+    fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>> {
+        self.0
+            .flag(name)
+            .map(|flag| Arc::new(CompiledFlag::clone(&flag)))
+    }
+
+    fn export_all(&self) -> HashMap<String, Arc<CompiledFlag>> {
+        self.0
+            .export_all()
+            .into_iter()
+            .map(|(key, flag)| (key, Arc::new(CompiledFlag::clone(&flag))))
+            .collect()
+    }
+
+    fn initialized(&self) -> bool {
+        self.0.initialized()
+    }
+}
+
+fn evaluate(c: &mut Criterion) {
+    let store = store();
+    let user = User::new("bench-user");
+
+    let mut group = c.benchmark_group("large_flag");
+    let shared = Evaluator::new(Arc::clone(&store));
+    group.bench_function("shared", |b| {
+        b.iter(|| shared.evaluate("large", &user).expect("evaluation failed"))
+    });
+    let cloned = Evaluator::new(CloningStore(store));
+    group.bench_function("cloned", |b| {
+        b.iter(|| cloned.evaluate("large", &user).expect("evaluation failed"))
+    });
+    group.finish();
+}
+
+fn target_match(c: &mut Criterion) {
+    let store = store();
+    let evaluator = Evaluator::new(store);
+    // listed in the last target
+    let user = User::new(format!(
+        "target-{}-{}",
+        VARIATIONS - 1,
+        TARGETS_PER_VARIATION - 1
+    ));
+    c.bench_function("target_match", |b| {
+        b.iter(|| {
+            evaluator
+                .evaluate("large", &user)
+                .expect("evaluation failed")
+        })
+    });
+}
+
+fn batch(c: &mut Criterion) {
+    let evaluator = Evaluator::new(store());
+    let user = User::new("bench-user");
+    let keys: Vec<_> = (0..BATCH_SIZE).map(batch_key).collect();
+
+    let mut group = c.benchmark_group("batch");
+    group.bench_function("single", |b| {
+        b.iter(|| {
+            for key in &keys {
+                evaluator.evaluate(key, &user).expect("evaluation failed");
+            }
+        })
+    });
+    group.bench_function("many", |b| {
+        b.iter(|| evaluator.evaluate_many(keys.iter().map(String::as_str), &user))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, evaluate, target_match, batch);
+criterion_main!(benches);
@@ -15,4 +15,10 @@ async fn main() {
     let mut client = DefaultClient::with_token(token.into()).expect("invalid token");
     client.start().await.expect("failed to start");
     dbg!(client.export());
+
+    // keep receiving updates until interrupted
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to listen for ctrl-c");
+    client.close().await;
 }
@@ -0,0 +1,282 @@
+use crate::{
+    evaluator::{Batch, Reason, User},
+    events::now_millis,
+    models::FeatureFlagState,
+    store::Store,
+};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::collections::HashMap;
+
+/// Options for [AllFlagsState::evaluate]
+#[derive(Debug, Clone, Default)]
+pub struct AllFlagsOptions {
+    /// Only include flags available to SDKs using the client-side ID
+    pub client_side_only: bool,
+    /// Include the evaluation reason for each flag
+    pub with_reasons: bool,
+    /// Omit version and reason for flags without event tracking
+    ///
+    /// Reduces the size of the payload.
+    pub details_only_for_tracked_flags: bool,
+}
+
+/// Metadata about the evaluation of a single flag
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlagState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variation: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
+    #[serde(skip_serializing_if = "is_false")]
+    pub track_events: bool,
+    /// Set for experiments, which need the reason in their events
+    #[serde(skip_serializing_if = "is_false")]
+    pub track_reason: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_events_until_date: Option<u64>,
+}
+
+/// Whether debug events are enabled for the flag right now
+fn debugging(flag: &FeatureFlagState) -> bool {
+    matches!(flag.debug_events_until_date, Some(until) if until > now_millis())
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+/// Evaluation results of all flags for a single user
+///
+/// Serializes to the bootstrap format of the JavaScript SDK:
+/// flag values keyed by flag, metadata under `$flagsState`
+/// and a `$valid` marker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllFlagsState {
+    values: HashMap<String, serde_json::Value>,
+    flags: HashMap<String, FlagState>,
+    valid: bool,
+}
+
+impl AllFlagsState {
+    /// Evaluate all flags from a [Store] for a user
+    pub fn evaluate<S: Store>(store: &S, user: &User, options: &AllFlagsOptions) -> Self {
+        let mut state = Self {
+            values: HashMap::new(),
+            flags: HashMap::new(),
+            valid: true,
+        };
+        let batch = Batch::new(store, user);
+        for (key, flag) in batch.snapshot().flags() {
+            if flag.deleted || (options.client_side_only && !client_side(flag)) {
+                continue;
+            }
+            let (value, variation, reason) = match batch.evaluate_flag(flag) {
+                Ok(detail) => (detail.value, Some(detail.variation_index), detail.reason),
+                Err(e) => (
+                    serde_json::Value::Null,
+                    None,
+                    Reason::Error {
+                        error_kind: e.kind(),
+                    },
+                ),
+            };
+            let experiment = reason.is_experiment(flag);
+            let details = !options.details_only_for_tracked_flags
+                || flag.track_events
+                || experiment
+                || debugging(flag);
+            state.values.insert(key.clone(), value);
+            state.flags.insert(
+                key.clone(),
+                FlagState {
+                    variation,
+                    version: Some(flag.version).filter(|_| details),
+                    reason: Some(reason)
+                        .filter(|_| experiment || (details && options.with_reasons)),
+                    track_events: flag.track_events || experiment,
+                    track_reason: experiment,
+                    debug_events_until_date: flag.debug_events_until_date,
+                },
+            );
+        }
+        state
+    }
+
+    /// State for when flags could not be evaluated,
+    /// e.g. because the store is not initialized yet
+    pub fn invalid() -> Self {
+        Self {
+            values: HashMap::new(),
+            flags: HashMap::new(),
+            valid: false,
+        }
+    }
+
+    /// Whether the state contains actual evaluation results
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    /// Value of a single flag
+    pub fn value(&self, key: &str) -> Option<&serde_json::Value> {
+        self.values.get(key)
+    }
+
+    /// Metadata about the evaluation of a single flag
+    pub fn flag_state(&self, key: &str) -> Option<&FlagState> {
+        self.flags.get(key)
+    }
+}
+
+/// Whether the flag is available to SDKs using the client-side ID
+fn client_side(flag: &FeatureFlagState) -> bool {
+    flag.client_side_availability
+        .using_environment_id
+        .unwrap_or(flag.client_side)
+}
+
+impl Serialize for AllFlagsState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.values.len() + 2))?;
+        for (key, value) in &self.values {
+            map.serialize_entry(key, value)?;
+        }
+        map.serialize_entry("$flagsState", &self.flags)?;
+        map.serialize_entry("$valid", &self.valid)?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllFlagsOptions, AllFlagsState};
+    use crate::{
+        evaluator::User,
+        events::now_millis,
+        models::client_side_availability::ClientSideAvailability,
+        test_utils::{FlagBuilder, MockStore},
+    };
+    use serde_json::json;
+
+    fn store() -> MockStore {
+        let mut store = MockStore::new();
+        let mut client_flag = FlagBuilder::default()
+            .with_key("client_flag")
+            .add_target(1, "test-user")
+            .track_events()
+            .into_inner();
+        client_flag.version = 4;
+        client_flag.client_side_availability = ClientSideAvailability {
+            using_environment_id: Some(true),
+            ..Default::default()
+        };
+        store.add(client_flag);
+        let mut server_flag = FlagBuilder::default()
+            .with_key("server_flag")
+            .off()
+            .into_inner();
+        server_flag.version = 2;
+        server_flag.debug_events_until_date = Some(1_600_000_000_000);
+        store.add(server_flag);
+        let broken_flag = FlagBuilder::default()
+            .with_key("broken_flag")
+            .with_fallthrough_variation(5)
+            .into_inner();
+        store.add(broken_flag);
+        store
+    }
+
+    #[test]
+    fn bootstrap_format() {
+        let store = store();
+        let user = User::new("test-user");
+        let options = AllFlagsOptions {
+            with_reasons: true,
+            ..Default::default()
+        };
+        let state = AllFlagsState::evaluate(&store, &user, &options);
+        let json = serde_json::to_value(&state).expect("failed to serialize");
+        assert_eq!(
+            json,
+            json!({
+                "client_flag": true,
+                "server_flag": false,
+                "broken_flag": null,
+                "$flagsState": {
+                    "client_flag": {
+                        "variation": 1,
+                        "version": 4,
+                        "reason": { "kind": "TARGET_MATCH" },
+                        "trackEvents": true
+                    },
+                    "server_flag": {
+                        "variation": 0,
+                        "version": 2,
+                        "reason": { "kind": "OFF" },
+                        "debugEventsUntilDate": 1_600_000_000_000u64
+                    },
+                    "broken_flag": {
+                        "version": 0,
+                        "reason": { "kind": "ERROR", "errorKind": "MALFORMED_FLAG" }
+                    }
+                },
+                "$valid": true
+            })
+        );
+    }
+
+    #[test]
+    fn client_side_only() {
+        let store = store();
+        let user = User::new("test-user");
+        let options = AllFlagsOptions {
+            client_side_only: true,
+            ..Default::default()
+        };
+        let state = AllFlagsState::evaluate(&store, &user, &options);
+        assert_eq!(Some(&true.into()), state.value("client_flag"));
+        assert_eq!(None, state.value("server_flag"));
+        assert_eq!(None, state.value("broken_flag"));
+        // reasons are opt-in
+        assert_eq!(None, state.flag_state("client_flag").unwrap().reason);
+    }
+
+    #[test]
+    fn details_only_for_tracked_flags() {
+        let mut store = store();
+        let mut debugged_flag = FlagBuilder::default()
+            .with_key("debugged_flag")
+            .into_inner();
+        debugged_flag.version = 3;
+        debugged_flag.debug_events_until_date = Some(now_millis() + 3_600_000);
+        store.add(debugged_flag);
+        let user = User::new("test-user");
+        let options = AllFlagsOptions {
+            with_reasons: true,
+            details_only_for_tracked_flags: true,
+            ..Default::default()
+        };
+        let state = AllFlagsState::evaluate(&store, &user, &options);
+        let tracked = state.flag_state("client_flag").unwrap();
+        assert_eq!(Some(4), tracked.version);
+        assert!(tracked.reason.is_some());
+        let debugged = state.flag_state("debugged_flag").unwrap();
+        assert_eq!(Some(3), debugged.version);
+        let untracked = state.flag_state("broken_flag").unwrap();
+        assert_eq!(None, untracked.version);
+        assert_eq!(None, untracked.reason);
+        // debugging expired in the past
+        let expired = state.flag_state("server_flag").unwrap();
+        assert_eq!(None, expired.version);
+        assert_eq!(None, expired.reason);
+    }
+
+    #[test]
+    fn invalid() {
+        let json = serde_json::to_value(AllFlagsState::invalid()).expect("failed to serialize");
+        assert_eq!(json, json!({ "$flagsState": {}, "$valid": false }));
+    }
+}
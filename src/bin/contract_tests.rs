@@ -0,0 +1,596 @@
+//! Test service for the LaunchDarkly SDK contract tests
+//!
+//! Implements the REST API the [test harness](https://github.com/launchdarkly/sdk-test-harness)
+//! uses to drive SDK clients:
+//!
+//! - `GET /`: name and capabilities of the SDK
+//! - `POST /`: create a client, its URL is returned in the `Location` header
+//! - `POST /clients/{id}`: run a command on a client
+//! - `DELETE /clients/{id}`: close a client
+//! - `DELETE /`: stop the service
+//!
+//! Listens on `CONTRACT_TESTS_ADDR`, defaulting to `0.0.0.0:8000`.
+//! Logs are filtered through `RUST_LOG`.
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use launchdarkly_rust_sdk_alt::{
+    all_flags::AllFlagsOptions,
+    diagnostics::Diagnostics,
+    evaluator::{ErrorKind, Reason, User},
+    events::{self, EventProcessor, HttpEventSender},
+    source::SseSource,
+    store::MemoryStore,
+    DefaultClient,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    env,
+    error::Error as StdError,
+    net::SocketAddr,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+const DEFAULT_ADDR: &str = "0.0.0.0:8000";
+
+const DEFAULT_STREAM_BASE_URI: &str = "https://stream.launchdarkly.com";
+const DEFAULT_EVENTS_BASE_URI: &str = "https://events.launchdarkly.com";
+
+/// Used when the harness doesn't specify how long to wait for the initial data
+const DEFAULT_START_WAIT: Duration = Duration::from_secs(5);
+
+/// Optional features of the harness supported by this SDK
+const CAPABILITIES: &[&str] = &[
+    "server-side",
+    "all-flags-with-reasons",
+    "all-flags-client-side-only",
+    "all-flags-details-only-for-tracked-flags",
+];
+
+type Client = DefaultClient<MemoryStore, SseSource>;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateParams {
+    tag: String,
+    configuration: Configuration,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    credential: String,
+    start_wait_time_ms: Option<u64>,
+    #[serde(default)]
+    init_can_fail: bool,
+    streaming: Option<StreamingParams>,
+    /// Events are disabled unless configured
+    events: Option<EventParams>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamingParams {
+    base_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventParams {
+    base_uri: Option<String>,
+    capacity: Option<usize>,
+    #[serde(default)]
+    enable_diagnostics: bool,
+    #[serde(default)]
+    all_attributes_private: bool,
+    #[serde(default)]
+    global_private_attributes: HashSet<String>,
+    flush_interval_ms: Option<u64>,
+    #[serde(default)]
+    inline_users: bool,
+}
+
+/// Command to run on a client, the parameters are in the field
+/// named after the command
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommandParams {
+    command: String,
+    evaluate: Option<EvaluateParams>,
+    evaluate_all: Option<EvaluateAllParams>,
+    identify_event: Option<IdentifyEventParams>,
+    custom_event: Option<CustomEventParams>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EvaluateParams {
+    flag_key: String,
+    user: User<'static>,
+    value_type: String,
+    default_value: Value,
+    #[serde(default)]
+    detail: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EvaluateAllParams {
+    user: User<'static>,
+    #[serde(default)]
+    with_reasons: bool,
+    #[serde(default)]
+    client_side_only: bool,
+    #[serde(default)]
+    details_only_for_tracked_flags: bool,
+}
+
+#[derive(Deserialize)]
+struct IdentifyEventParams {
+    user: User<'static>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CustomEventParams {
+    event_key: String,
+    user: User<'static>,
+    /// `null` is sent without data
+    data: Option<Value>,
+    metric_value: Option<f64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EvaluateResponse {
+    value: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variation_index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<Reason>,
+}
+
+/// Clients created by the harness, by id
+struct Service {
+    clients: Mutex<HashMap<u64, Arc<Client>>>,
+    next_id: AtomicU64,
+    shutdown: Notify,
+}
+
+impl Service {
+    fn new() -> Self {
+        Self {
+            clients: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            shutdown: Notify::new(),
+        }
+    }
+
+    /// Register a client, returns its URL
+    fn add(&self, client: Client) -> String {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.clients.lock().unwrap().insert(id, Arc::new(client));
+        format!("/clients/{}", id)
+    }
+
+    fn client(&self, id: u64) -> Option<Arc<Client>> {
+        self.clients.lock().unwrap().get(&id).cloned()
+    }
+
+    fn remove(&self, id: u64) -> Option<Arc<Client>> {
+        self.clients.lock().unwrap().remove(&id)
+    }
+}
+
+async fn handle(service: Arc<Service>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let id = req
+        .uri()
+        .path()
+        .strip_prefix("/clients/")
+        .and_then(|id| id.parse().ok());
+    let res = match (method, req.uri().path(), id) {
+        (Method::GET, "/", _) => json(&json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "clientVersion": env!("CARGO_PKG_VERSION"),
+            "capabilities": CAPABILITIES,
+        })),
+        (Method::POST, "/", _) => match parse::<CreateParams>(req).await {
+            Some(params) => create(&service, params).await,
+            None => status(StatusCode::BAD_REQUEST),
+        },
+        (Method::DELETE, "/", _) => {
+            service.shutdown.notify_one();
+            status(StatusCode::OK)
+        }
+        (Method::POST, _, Some(id)) => match (service.client(id), parse(req).await) {
+            (None, _) => status(StatusCode::NOT_FOUND),
+            (Some(client), Some(params)) => run(&client, params)
+                .await
+                .unwrap_or_else(|| status(StatusCode::BAD_REQUEST)),
+            (Some(_), None) => status(StatusCode::BAD_REQUEST),
+        },
+        (Method::DELETE, _, Some(id)) => match service.remove(id) {
+            Some(client) => {
+                client.close().await;
+                status(StatusCode::OK)
+            }
+            None => status(StatusCode::NOT_FOUND),
+        },
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    Ok(res)
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = code;
+    res
+}
+
+fn json<T: Serialize>(data: &T) -> Response<Body> {
+    let body = serde_json::to_vec(data).expect("responses are always serializable");
+    let mut res = Response::new(Body::from(body));
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+/// JSON body of a request, `None` when it's malformed
+async fn parse<T: DeserializeOwned>(req: Request<Body>) -> Option<T> {
+    let body = hyper::body::to_bytes(req.into_body()).await.ok()?;
+    match serde_json::from_slice(&body) {
+        Ok(params) => Some(params),
+        Err(error) => {
+            warn!(%error, "invalid request body");
+            None
+        }
+    }
+}
+
+async fn create(service: &Service, params: CreateParams) -> Response<Body> {
+    let tag = params.tag;
+    match create_client(params.configuration).await {
+        Ok(client) => {
+            let location = service.add(client);
+            info!(%tag, %location, "created client");
+            let mut res = status(StatusCode::CREATED);
+            res.headers_mut().insert(
+                header::LOCATION,
+                header::HeaderValue::from_str(&location).expect("client URL is a valid header"),
+            );
+            res
+        }
+        Err(error) => {
+            warn!(%tag, %error, "failed to create client");
+            let mut res = Response::new(Body::from(error.to_string()));
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            res
+        }
+    }
+}
+
+async fn create_client(config: Configuration) -> Result<Client, Box<dyn StdError + Send + Sync>> {
+    let credential = config.credential;
+    let stream_base = config
+        .streaming
+        .and_then(|streaming| streaming.base_uri)
+        .unwrap_or_else(|| DEFAULT_STREAM_BASE_URI.into());
+    let source = SseSource::with_url(
+        format!("{}/all", stream_base.trim_end_matches('/')),
+        &credential,
+    )?;
+    let mut client = DefaultClient::new(MemoryStore::new(), source);
+
+    if let Some(params) = config.events {
+        let base = params
+            .base_uri
+            .unwrap_or_else(|| DEFAULT_EVENTS_BASE_URI.into());
+        let base = base.trim_end_matches('/');
+        let sender = HttpEventSender::with_urls(
+            &credential,
+            format!("{}/bulk", base).parse()?,
+            format!("{}/diagnostic", base).parse()?,
+        )?;
+        let defaults = events::Config::default();
+        let events_config = events::Config {
+            capacity: params.capacity.unwrap_or(defaults.capacity),
+            flush_interval: params
+                .flush_interval_ms
+                .map_or(defaults.flush_interval, Duration::from_millis),
+            all_attributes_private: params.all_attributes_private,
+            private_attribute_names: params.global_private_attributes,
+            inline_users_in_events: params.inline_users,
+            diagnostic_opt_out: !params.enable_diagnostics,
+            ..defaults
+        };
+        let diagnostics = Arc::new(Diagnostics::new(&credential));
+        let processor = EventProcessor::with_diagnostics(sender, events_config, diagnostics);
+        client = client.with_events(processor);
+    }
+
+    let wait = config
+        .start_wait_time_ms
+        .map_or(DEFAULT_START_WAIT, Duration::from_millis);
+    match client.start_with_timeout(wait).await {
+        Ok(()) => {}
+        // keeps reading in the background
+        Err(error) if config.init_can_fail => warn!(%error, "client failed to initialize"),
+        Err(error) => return Err(error.into()),
+    }
+    Ok(client)
+}
+
+/// Run a command on a client, `None` for unknown or incomplete commands
+async fn run(client: &Client, params: CommandParams) -> Option<Response<Body>> {
+    let res = match params.command.as_str() {
+        "evaluate" => json(&evaluate(client, params.evaluate?)),
+        "evaluateAll" => {
+            let params = params.evaluate_all?;
+            let options = AllFlagsOptions {
+                client_side_only: params.client_side_only,
+                with_reasons: params.with_reasons,
+                details_only_for_tracked_flags: params.details_only_for_tracked_flags,
+            };
+            let state = client.all_flags_state(&params.user, options);
+            json(&json!({ "state": state }))
+        }
+        "identifyEvent" => {
+            client.identify(&params.identify_event?.user);
+            status(StatusCode::ACCEPTED)
+        }
+        "customEvent" => {
+            let params = params.custom_event?;
+            client.track(
+                &params.event_key,
+                &params.user,
+                params.data,
+                params.metric_value,
+            );
+            status(StatusCode::ACCEPTED)
+        }
+        "flushEvents" => {
+            client.flush().await;
+            status(StatusCode::ACCEPTED)
+        }
+        _ => return None,
+    };
+    Some(res)
+}
+
+fn evaluate(client: &Client, params: EvaluateParams) -> EvaluateResponse {
+    let EvaluateParams {
+        flag_key,
+        user,
+        value_type,
+        default_value,
+        detail: with_detail,
+    } = params;
+    let error = |kind| EvaluateResponse {
+        value: default_value.clone(),
+        variation_index: None,
+        reason: Some(Reason::Error { error_kind: kind }).filter(|_| with_detail),
+    };
+    if !client.initialized() {
+        return error(ErrorKind::ClientNotReady);
+    }
+    let detail = client.variation_detail(&flag_key, &user, &default_value, |value| {
+        type_matches(&value_type, value)
+    });
+    match detail {
+        Ok(detail) if with_detail => EvaluateResponse {
+            value: detail.value,
+            variation_index: Some(detail.variation_index),
+            reason: Some(detail.reason),
+        },
+        Ok(detail) => EvaluateResponse {
+            value: detail.value,
+            variation_index: None,
+            reason: None,
+        },
+        Err(e) => error(e.kind()),
+    }
+}
+
+/// Whether a value has the type the harness asked for
+fn type_matches(value_type: &str, value: &Value) -> bool {
+    match value_type {
+        "bool" => value.is_boolean(),
+        "int" => value.is_i64() || value.is_u64(),
+        "double" => value.is_number(),
+        "string" => value.is_string(),
+        _ => true,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let addr: SocketAddr = match env::var("CONTRACT_TESTS_ADDR")
+        .unwrap_or_else(|_| DEFAULT_ADDR.into())
+        .parse()
+    {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Invalid CONTRACT_TESTS_ADDR: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let service = Arc::new(Service::new());
+    let shared = Arc::clone(&service);
+    let make_service = make_service_fn(move |_| {
+        let service = Arc::clone(&shared);
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&service), req))) }
+    });
+    let shutdown = Arc::clone(&service);
+    let server = Server::bind(&addr)
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            tokio::select! {
+                _ = shutdown.shutdown.notified() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        });
+    info!(%addr, "test service listening");
+    if let Err(e) = server.await {
+        eprintln!("Server failed: {}", e);
+    }
+
+    let clients: Vec<_> = service.clients.lock().unwrap().drain().collect();
+    for (_, client) in clients {
+        client.close().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, Service};
+    use futures::FutureExt;
+    use hyper::{Body, Method, Request, StatusCode};
+    use launchdarkly_rust_sdk_alt::{
+        consumer::Consumer,
+        message::{InitData, Message},
+        source::SseSource,
+        store::MemoryStore,
+        DefaultClient,
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    /// Service with a single initialized client
+    fn service() -> (Arc<Service>, String) {
+        let flag = serde_json::from_value(json!({
+            "key": "flag",
+            "version": 1,
+            "on": true,
+            "salt": "salt",
+            "prerequisites": [],
+            "targets": [{ "variation": 1, "values": ["targeted"] }],
+            "rules": [],
+            "fallthrough": { "variation": 0 },
+            "offVariation": 0,
+            "variations": ["a", "b"],
+            "clientSideAvailability": {},
+            "clientSide": false,
+            "trackEvents": false,
+            "trackEventsFallthrough": false,
+            "debugEventsUntilDate": null,
+            "deleted": false
+        }))
+        .unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let flags = vec![("flag".to_string(), flag)].into_iter().collect();
+        <MemoryStore as Consumer<SseSource>>::consume(
+            &store,
+            Message::Put(InitData::with_flags(flags)),
+        )
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+        let client = DefaultClient::new(store, SseSource::new("sdk-key"));
+        let service = Arc::new(Service::new());
+        let location = service.add(client);
+        (service, location)
+    }
+
+    async fn request(
+        service: &Arc<Service>,
+        method: Method,
+        path: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let res = handle(Arc::clone(service), req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn evaluate(user: &str, value_type: &str) -> Value {
+        json!({
+            "command": "evaluate",
+            "evaluate": {
+                "flagKey": "flag",
+                "user": { "key": user, "custom": { "team": "a" } },
+                "valueType": value_type,
+                "defaultValue": "default",
+                "detail": true
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let service = Arc::new(Service::new());
+        let (status, body) = request(&service, Method::GET, "/", Value::Null).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body["capabilities"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("server-side")));
+    }
+
+    #[tokio::test]
+    async fn evaluate_detail() {
+        let (service, location) = service();
+        let (status, body) = request(
+            &service,
+            Method::POST,
+            &location,
+            evaluate("targeted", "string"),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            json!({ "value": "b", "variationIndex": 1, "reason": { "kind": "TARGET_MATCH" } }),
+            body
+        );
+
+        let (_, body) = request(&service, Method::POST, &location, evaluate("other", "bool")).await;
+        assert_eq!(
+            json!({ "value": "default", "reason": { "kind": "ERROR", "errorKind": "WRONG_TYPE" } }),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_commands() {
+        let (service, location) = service();
+        let (status, _) = request(
+            &service,
+            Method::POST,
+            &location,
+            json!({ "command": "unknown" }),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        let (status, _) = request(&service, Method::POST, "/clients/9", evaluate("a", "any")).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        let (status, _) = request(&service, Method::DELETE, &location, Value::Null).await;
+        assert_eq!(StatusCode::OK, status);
+        let (status, _) = request(&service, Method::POST, &location, evaluate("a", "any")).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+}
@@ -0,0 +1,262 @@
+//! Evaluate a flag for a user, for debugging flag behavior offline
+//!
+//! ```text
+//! eval (--snapshot FILE | --data FILE | --token SDK_KEY) FLAG USER
+//! ```
+//!
+//! Flag data is read from a [snapshot](launchdarkly_rust_sdk_alt::snapshot::Snapshot),
+//! a JSON file with all flags in the `put` format (`{"flags": {...}}`),
+//! or from LaunchDarkly using an SDK key.
+//!
+//! The user is given as JSON in the format of the SDKs, e.g.
+//! `{"key": "user-a", "custom": {"team": "platform"}}`,
+//! or as a plain user key.
+//!
+//! Set `RUST_LOG`, e.g. to `warn`, to see problems with the flag data.
+
+use futures::FutureExt;
+use launchdarkly_rust_sdk_alt::{
+    consumer::Consumer,
+    evaluator::{Detail, Evaluator, Reason, User},
+    message::{InitData, Message},
+    models::FeatureFlagState,
+    snapshot::Snapshot,
+    source::SseSource,
+    store::{MemoryStore, Store},
+};
+use std::{
+    env, error::Error as StdError, fmt::Write, fs::File, io::BufReader, process, sync::Arc,
+    time::Duration,
+};
+use tokio::time;
+
+const USAGE: &str = "Usage: eval (--snapshot FILE | --data FILE | --token SDK_KEY) FLAG USER";
+
+/// How long to wait for the flags when reading from LaunchDarkly
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Where to read the flag data from
+#[derive(Debug, PartialEq)]
+enum DataSource {
+    Snapshot(String),
+    InitData(String),
+    Token(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    source: DataSource,
+    flag: String,
+    user: String,
+}
+
+impl Args {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Option<Self> {
+        let mut args = args.into_iter();
+        let source = match (args.next()?.as_str(), args.next()?) {
+            ("--snapshot", path) => DataSource::Snapshot(path),
+            ("--data", path) => DataSource::InitData(path),
+            ("--token", token) => DataSource::Token(token),
+            _ => return None,
+        };
+        let flag = args.next()?;
+        let user = args.next()?;
+        if args.next().is_some() {
+            return None;
+        }
+        Some(Self { source, flag, user })
+    }
+}
+
+/// Store filled from the data source
+async fn load(source: DataSource) -> Result<Arc<MemoryStore>, BoxError> {
+    let store = Arc::new(MemoryStore::new());
+    let msg = match source {
+        DataSource::Snapshot(path) => Snapshot::load(path)?.into(),
+        DataSource::InitData(path) => {
+            let data: InitData = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            Message::Put(data)
+        }
+        DataSource::Token(token) => {
+            let (reader, init) = Arc::clone(&store).read_from_with_handle(SseSource::new(token));
+            let result = time::timeout(START_TIMEOUT, init).await;
+            reader.close().await;
+            result.map_err(|_| "timed out waiting for flags from LaunchDarkly")??;
+            return Ok(store);
+        }
+    };
+    <MemoryStore as Consumer<SseSource>>::consume(&store, msg)
+        .now_or_never()
+        .expect("memory store consumes synchronously")?;
+    Ok(store)
+}
+
+/// JSON user, or a plain key
+fn parse_user(arg: &str) -> Result<User<'static>, serde_json::Error> {
+    if arg.trim_start().starts_with('{') {
+        serde_json::from_str(arg)
+    } else {
+        Ok(User::new(arg.to_string()))
+    }
+}
+
+/// Human readable explanation of an evaluation
+fn describe(flag: &FeatureFlagState, user: &User, detail: &Detail) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "flag:      {} (version {})", flag.key, flag.version);
+    let _ = writeln!(out, "user:      {}", user.key());
+    let _ = writeln!(out, "value:     {}", detail.value);
+    let _ = writeln!(out, "variation: {}", detail.variation_index);
+    let reason = serde_json::to_string(&detail.reason).expect("reasons are serializable");
+    let _ = writeln!(out, "reason:    {}", reason);
+    match &detail.reason {
+        Reason::RuleMatch { rule_index, .. } => {
+            let clauses = flag
+                .rules
+                .get(*rule_index)
+                .and_then(|rule| rule.clauses.as_ref());
+            // a rule matches when all of its clauses do
+            for (index, clause) in clauses.into_iter().flatten().enumerate() {
+                let attribute = clause.attribute.as_deref().unwrap_or_default();
+                let value = user
+                    .attribute(attribute)
+                    .map_or_else(|| "missing".into(), |value| value.to_string());
+                let negate = if clause.negate == Some(true) {
+                    "not "
+                } else {
+                    ""
+                };
+                let _ = writeln!(
+                    out,
+                    "  clause {}: {}{} {} {} (user: {})",
+                    index,
+                    negate,
+                    attribute,
+                    clause.op.as_deref().unwrap_or_default(),
+                    serde_json::to_string(&clause.values).expect("values are serializable"),
+                    value,
+                );
+            }
+        }
+        Reason::TargetMatch => {
+            let _ = writeln!(out, "  user key is targeted individually");
+        }
+        _ => {}
+    }
+    out
+}
+
+async fn run(args: Args) -> Result<String, BoxError> {
+    let Args { source, flag, user } = args;
+    let user = parse_user(&user).map_err(|e| format!("Invalid user: {}", e))?;
+    let store = load(source).await?;
+    if let Some(diagnostics) = store.diagnostics().get(&flag) {
+        for diagnostic in diagnostics {
+            eprintln!("warning: {}", diagnostic);
+        }
+    }
+    let flag = store
+        .flag(&flag)
+        .ok_or_else(|| format!("Flag {} not found", flag))?;
+    let detail = Evaluator::new(Arc::clone(&store)).evaluate_flag(&flag, &user)?;
+    Ok(describe(&flag, &user, &detail))
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let args = match Args::parse(env::args().skip(1)) {
+        Some(args) => args,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    match run(args).await {
+        Ok(description) => print!("{}", description),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_user, run, Args, DataSource};
+    use std::{env, fs, process};
+
+    fn args(args: &[&str]) -> Option<Args> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(
+            Some(Args {
+                source: DataSource::Snapshot("flags.json".into()),
+                flag: "my-flag".into(),
+                user: "user-a".into(),
+            }),
+            args(&["--snapshot", "flags.json", "my-flag", "user-a"])
+        );
+        assert_eq!(None, args(&["--snapshot", "flags.json", "my-flag"]));
+        assert_eq!(None, args(&["--file", "flags.json", "my-flag", "user-a"]));
+        assert_eq!(
+            None,
+            args(&["--data", "flags.json", "my-flag", "user-a", "extra"])
+        );
+
+        let user = parse_user(r#"{"key": "user-a", "custom": {"team": "a"}}"#).unwrap();
+        assert_eq!("user-a", user.key());
+        assert_eq!("user-b", parse_user("user-b").unwrap().key());
+    }
+
+    #[tokio::test]
+    async fn explain_rule_match() {
+        let data = serde_json::json!({
+            "flags": {
+                "my-flag": {
+                    "key": "my-flag",
+                    "version": 2,
+                    "on": true,
+                    "salt": "salt",
+                    "prerequisites": [],
+                    "targets": [],
+                    "rules": [{
+                        "id": "rule-a",
+                        "variation": 1,
+                        "clauses": [{ "attribute": "team", "op": "in", "values": ["a"], "negate": false }]
+                    }],
+                    "fallthrough": { "variation": 0 },
+                    "offVariation": 0,
+                    "variations": [false, true],
+                    "clientSideAvailability": {},
+                    "clientSide": false,
+                    "trackEvents": false,
+                    "trackEventsFallthrough": false,
+                    "debugEventsUntilDate": null,
+                    "deleted": false
+                }
+            },
+            "segments": {}
+        });
+        let path = env::temp_dir().join(format!("ld-eval-{}.json", process::id()));
+        fs::write(&path, data.to_string()).unwrap();
+        let args = Args {
+            source: DataSource::InitData(path.to_string_lossy().into()),
+            flag: "my-flag".into(),
+            user: r#"{"key": "user-a", "custom": {"team": "a"}}"#.into(),
+        };
+        let description = run(args).await;
+        let _ = fs::remove_file(&path);
+
+        let description = description.unwrap();
+        assert!(description.contains("value:     true\n"));
+        assert!(description.contains("variation: 1\n"));
+        assert!(description.contains(r#""kind":"RULE_MATCH","ruleIndex":0,"ruleId":"rule-a""#));
+        assert!(description.contains("  clause 0: team in [\"a\"] (user: \"a\")\n"));
+    }
+}
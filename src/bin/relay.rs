@@ -0,0 +1,442 @@
+//! Lightweight relay proxy
+//!
+//! Consumes the LaunchDarkly stream once and serves the flag data
+//! to other SDK instances:
+//!
+//! - `GET /all`: SSE stream starting with a `put` of all flags and segments,
+//!   followed by every `patch` and `delete`
+//! - `GET /sdk/latest-all`: all flags and segments, for polling
+//!
+//! Downstream SDKs authenticate with the same SDK key as the relay.
+//! The relay exits when LaunchDarkly rejects the key.
+//!
+//! Configured through the environment:
+//!
+//! - `LD_SDK_KEY`: SDK key of the environment, required
+//! - `RELAY_ADDR`: address to listen on, defaults to `0.0.0.0:8030`
+//! - `RUST_LOG`: log filter, e.g. `info`
+
+use futures::{stream, StreamExt};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use launchdarkly_rust_sdk_alt::{
+    consumer::{Consumer, InitState, ReadError},
+    message::{InitData, Message},
+    models::{FeatureFlagState, SegmentState},
+    source::SseSource,
+    store::{MemoryStore, Store},
+};
+use std::{convert::Infallible, env, net::SocketAddr, process, sync::Arc, time::Duration};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+use tracing::{error, info, warn};
+
+const DEFAULT_ADDR: &str = "0.0.0.0:8030";
+
+/// Updates buffered for a slow client, it receives a new `put` when falling behind
+const FANOUT_CAPACITY: usize = 1024;
+
+/// Keeps idle connections from timing out
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(180);
+
+/// SSE event sent to downstream SDKs
+#[derive(Debug)]
+struct Event {
+    name: &'static str,
+    data: String,
+}
+
+impl Event {
+    /// Fails for messages without a wire format
+    fn from_message(msg: &Message) -> Option<Self> {
+        let data = serde_json::to_string(msg).ok()?;
+        Some(Self {
+            name: msg.event_type(),
+            data,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.name, self.data)
+    }
+}
+
+/// Store fanning out every update to the connected clients
+struct Relay {
+    store: MemoryStore,
+    updates: broadcast::Sender<Arc<Event>>,
+    sdk_key: String,
+}
+
+impl Relay {
+    fn new(sdk_key: String) -> Self {
+        let (updates, _) = broadcast::channel(FANOUT_CAPACITY);
+        Self {
+            store: MemoryStore::new(),
+            updates,
+            sdk_key,
+        }
+    }
+
+    /// All flags and segments in the store as a `put` event
+    fn put(&self) -> Event {
+        let flags = self
+            .store
+            .export_all()
+            .into_iter()
+            .map(|(key, flag)| (key, FeatureFlagState::clone(&flag)))
+            .collect();
+        let segments = self
+            .store
+            .export_segments()
+            .into_iter()
+            .map(|(key, segment)| (key, SegmentState::clone(&segment)))
+            .collect();
+        Event::from_message(&Message::Put(InitData { flags, segments }))
+            .expect("put is always serializable")
+    }
+}
+
+impl<S> Consumer<S> for Relay {
+    type Error = Infallible;
+    type Future = futures::future::Ready<Result<InitState, Self::Error>>;
+
+    fn consume(&self, msg: Message) -> Self::Future {
+        let event = Event::from_message(&msg);
+        // messages ignored by the store are not forwarded
+        if let (true, Some(event)) = (self.store.apply(msg), event) {
+            // fails only without connected clients
+            let _ = self.updates.send(Arc::new(event));
+        }
+        let state = if self.store.initialized() {
+            InitState::Done
+        } else {
+            InitState::Pending
+        };
+        futures::future::ready(Ok(state))
+    }
+}
+
+async fn handle(relay: Arc<Relay>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let authorized = matches!(
+        req.headers().get(header::AUTHORIZATION),
+        Some(key) if key == relay.sdk_key.as_str()
+    );
+    if !authorized {
+        return Ok(status(StatusCode::UNAUTHORIZED));
+    }
+    if !relay.store.initialized() {
+        return Ok(status(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    let res = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/all") => stream_all(relay),
+        (&Method::GET, "/sdk/latest-all") => latest_all(&relay),
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    Ok(res)
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = code;
+    res
+}
+
+/// Stream of all flags, followed by the updates
+fn stream_all(relay: Arc<Relay>) -> Response<Body> {
+    // subscribe before reading the store, no update is missed
+    let updates = relay.updates.subscribe();
+    let put = relay.put().encode();
+    let updates = stream::unfold((relay, updates), |(relay, mut updates)| async move {
+        let chunk = match updates.recv().await {
+            Ok(event) => event.encode(),
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "client fell behind, sending all flags");
+                relay.put().encode()
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((chunk, (relay, updates)))
+    });
+    let heartbeats = stream::unfold(
+        time::interval_at(
+            time::Instant::now() + HEARTBEAT_INTERVAL,
+            HEARTBEAT_INTERVAL,
+        ),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((":\n\n".to_string(), interval))
+        },
+    );
+    let events = stream::once(async { put }).chain(updates);
+    let body = stream::select(events, heartbeats).map(Ok::<_, Infallible>);
+
+    let mut res = Response::new(Body::wrap_stream(body));
+    let headers = res.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-cache"),
+    );
+    res
+}
+
+/// All flags and segments in the format of the polling endpoint
+fn latest_all(relay: &Relay) -> Response<Body> {
+    let data = serde_json::json!({
+        "flags": relay.store.export_all(),
+        "segments": relay.store.export_segments(),
+    });
+    let mut res = Response::new(Body::from(data.to_string()));
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let sdk_key = match env::var("LD_SDK_KEY") {
+        Ok(key) => key,
+        Err(_) => {
+            eprintln!("Please set the SDK key in LD_SDK_KEY");
+            process::exit(1);
+        }
+    };
+    let addr: SocketAddr = match env::var("RELAY_ADDR")
+        .unwrap_or_else(|_| DEFAULT_ADDR.into())
+        .parse()
+    {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Invalid RELAY_ADDR: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let source = SseSource::new(&sdk_key);
+    let relay = Arc::new(Relay::new(sdk_key));
+    let (reader, init) = Arc::clone(&relay).read_from_with_handle(source);
+    tokio::spawn(async move {
+        match init.await {
+            Ok(()) => info!("received flags from LaunchDarkly"),
+            Err(ReadError::Rejected(reason)) => {
+                error!(%reason, "LaunchDarkly rejected the SDK key");
+                process::exit(1);
+            }
+            Err(error) => error!(%error, "failed to read flags from LaunchDarkly"),
+        }
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let relay = Arc::clone(&relay);
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&relay), req))) }
+    });
+    let server = Server::bind(&addr).serve(make_service);
+    info!(%addr, "relay listening");
+
+    // open streams never end, so don't wait for them on shutdown
+    tokio::select! {
+        res = server => {
+            if let Err(e) = res {
+                eprintln!("Server failed: {}", e);
+            }
+        }
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    reader.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, Relay};
+    use futures::{FutureExt, StreamExt};
+    use hyper::{header, Body, Request, StatusCode};
+    use launchdarkly_rust_sdk_alt::{
+        consumer::Consumer,
+        message::{InitData, Message, Update},
+        models::{FeatureFlagState, SegmentState},
+        source::SseSource,
+        store::Store,
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn flag(key: &str, version: u64) -> FeatureFlagState {
+        serde_json::from_value(json!({
+            "key": key,
+            "version": version,
+            "on": true,
+            "salt": "salt",
+            "prerequisites": [],
+            "targets": [],
+            "rules": [],
+            "fallthrough": { "variation": 0 },
+            "offVariation": 0,
+            "variations": [true, false],
+            "clientSideAvailability": {},
+            "clientSide": false,
+            "trackEvents": false,
+            "trackEventsFallthrough": false,
+            "debugEventsUntilDate": null,
+            "deleted": false
+        }))
+        .unwrap()
+    }
+
+    fn segment(key: &str, version: u64) -> SegmentState {
+        serde_json::from_value(json!({
+            "key": key,
+            "version": version,
+            "included": ["user-a"],
+            "excluded": [],
+            "rules": [],
+            "salt": "salt",
+            "deleted": false
+        }))
+        .unwrap()
+    }
+
+    fn consume(relay: &Relay, msg: Message) {
+        <Relay as Consumer<SseSource>>::consume(relay, msg)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+    }
+
+    fn request(path: &str, key: &str) -> Request<Body> {
+        Request::get(path)
+            .header(header::AUTHORIZATION, key)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn relay() -> Arc<Relay> {
+        let relay = Arc::new(Relay::new("sdk-key".into()));
+        let mut a = flag("a", 1);
+        // fields unknown to the SDK are passed through
+        a.extra
+            .insert("_site".into(), json!({ "href": "/flags/a" }));
+        let init = InitData {
+            flags: vec![("a".to_string(), a)].into_iter().collect(),
+            segments: vec![("s".to_string(), segment("s", 1))]
+                .into_iter()
+                .collect(),
+        };
+        consume(&relay, Message::Put(init));
+        relay
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let res = handle(relay(), request("/all", "other-key")).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        let uninitialized = Arc::new(Relay::new("sdk-key".into()));
+        let res = handle(uninitialized, request("/all", "sdk-key"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+    }
+
+    #[tokio::test]
+    async fn latest_all() {
+        let res = handle(relay(), request("/sdk/latest-all", "sdk-key"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let data: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(1, data["flags"]["a"]["version"]);
+        assert_eq!(json!({ "href": "/flags/a" }), data["flags"]["a"]["_site"]);
+        assert_eq!(1, data["segments"]["s"]["version"]);
+        assert_eq!(json!(["user-a"]), data["segments"]["s"]["included"]);
+    }
+
+    #[tokio::test]
+    async fn stream_fans_out_updates() {
+        let relay = relay();
+        let res = handle(Arc::clone(&relay), request("/all", "sdk-key"))
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+
+        let put = body.next().await.unwrap().unwrap();
+        let put = String::from_utf8(put.to_vec()).unwrap();
+        assert!(put.starts_with("event: put\ndata: {\"path\":\"/\""));
+
+        let update = Update::Flag {
+            name: "b".into(),
+            data: Some(flag("b", 1)),
+            version: None,
+        };
+        consume(&relay, Message::Patch(update));
+        let patch = body.next().await.unwrap().unwrap();
+        let patch = String::from_utf8(patch.to_vec()).unwrap();
+        assert!(patch.starts_with("event: patch\ndata: {\"path\":\"/flags/b\""));
+        assert!(patch.ends_with("\n\n"));
+    }
+
+    #[tokio::test]
+    async fn stream_skips_outdated_updates() {
+        let relay = relay();
+        let res = handle(Arc::clone(&relay), request("/all", "sdk-key"))
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+        body.next().await.unwrap().unwrap();
+
+        let patch = |version| {
+            Message::Patch(Update::Flag {
+                name: "a".into(),
+                data: Some(flag("a", version)),
+                version: None,
+            })
+        };
+        consume(&relay, patch(1));
+        consume(&relay, patch(2));
+        let patch = body.next().await.unwrap().unwrap();
+        let patch = String::from_utf8(patch.to_vec()).unwrap();
+        assert!(patch.starts_with("event: patch\ndata: {\"path\":\"/flags/a\""));
+        assert!(patch.contains("\"version\":2"));
+        assert_eq!(2, relay.store.flag("a").unwrap().version);
+    }
+
+    #[tokio::test]
+    async fn stream_forwards_segments() {
+        let relay = relay();
+        let res = handle(Arc::clone(&relay), request("/all", "sdk-key"))
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+
+        let put = body.next().await.unwrap().unwrap();
+        let put = String::from_utf8(put.to_vec()).unwrap();
+        assert!(put.contains("\"segments\":{\"s\":"));
+
+        let delete = |version| {
+            Message::Delete(Update::Segment {
+                name: "s".into(),
+                data: None,
+                version: Some(version),
+            })
+        };
+        consume(&relay, delete(1));
+        consume(&relay, delete(2));
+        let delete = body.next().await.unwrap().unwrap();
+        let delete = String::from_utf8(delete.to_vec()).unwrap();
+        assert!(delete.starts_with("event: delete\ndata: {\"path\":\"/segments/s\""));
+        assert!(delete.contains("\"version\":2"));
+        assert!(relay.store.export_segments().is_empty());
+    }
+}
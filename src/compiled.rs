@@ -0,0 +1,230 @@
+//! Flags prepared for evaluation
+//!
+//! A [Store](crate::store::Store) compiles every flag once when it receives it,
+//! so evaluations don't need to scan targets or parse clause values.
+
+use crate::{
+    evaluator::Error,
+    models::FeatureFlagState,
+    operators::Operator,
+    validation::{Diagnostic, Location, Problem, Validation},
+};
+use serde::{Serialize, Serializer};
+use std::{collections::HashMap, ops::Deref};
+
+/// Flag along with lookup structures for its targets and rules
+///
+/// Dereferences to the [FeatureFlagState] it was built from.
+#[derive(Debug, Clone)]
+pub struct CompiledFlag {
+    flag: FeatureFlagState,
+    /// Variation for each targeted user key, or the problem of its target
+    targets: HashMap<String, Result<usize, Error>>,
+    /// Compiled rules, in the same order as the rules of the flag
+    rules: Vec<CompiledRule>,
+    /// Problem of the fallthrough variation or rollout
+    fallthrough_error: Option<Error>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Clauses of a rule along with its problems
+///
+/// Problems only fail evaluations that get to the broken part of the rule.
+#[derive(Debug, Clone)]
+pub(crate) struct CompiledRule {
+    pub(crate) clauses: Vec<CompiledClause>,
+    /// Problem of a clause, fails evaluations reaching the rule
+    pub(crate) clause_error: Option<Error>,
+    /// Problem of the variation or rollout, fails evaluations matching the rule
+    pub(crate) error: Option<Error>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CompiledClause {
+    pub(crate) attribute: String,
+    pub(crate) operator: Operator,
+    pub(crate) negate: bool,
+}
+
+impl CompiledFlag {
+    /// Validate a flag and build the lookup structures for evaluating it
+    pub fn new(flag: FeatureFlagState) -> Self {
+        let mut validation = Validation::new(flag.variations.len());
+        validation.check_index(Location::OffVariation, flag.off_variation as i64);
+
+        for (index, prereq) in flag.prerequisites.iter().enumerate() {
+            if prereq.key.is_none() || prereq.variation.is_none() {
+                validation.report(Location::Prerequisite(index), Problem::InvalidPrerequisite);
+            }
+        }
+
+        let mut targets = HashMap::new();
+        for (index, target) in flag.targets.iter().enumerate() {
+            let location = Location::Target(index);
+            let start = validation.diagnostics.len();
+            let variation = match (&target.values, target.variation) {
+                (Some(_), Some(variation)) => {
+                    validation.check_index(location, variation);
+                    validation
+                        .first_error(start)
+                        .map_or(Ok(variation as usize), Err)
+                }
+                _ => {
+                    validation.report(location, Problem::InvalidTarget);
+                    Err(Error::InvalidTarget)
+                }
+            };
+            for value in target.values.iter().flatten() {
+                // the first target listing a user wins
+                targets
+                    .entry(value.clone())
+                    .or_insert_with(|| variation.clone());
+            }
+        }
+
+        let mut rules = Vec::with_capacity(flag.rules.len());
+        for (rule_index, rule) in flag.rules.iter().enumerate() {
+            let location = Location::Rule(rule_index);
+            let start = validation.diagnostics.len();
+            match (rule.variation, &rule.rollout) {
+                (Some(variation), _) => validation.check_index(location, variation),
+                (None, Some(rollout)) => validation.check_rollout(location, rollout),
+                (None, None) => validation.report(location, Problem::InvalidRule),
+            }
+            let error = validation.first_error(start);
+            let start = validation.diagnostics.len();
+            let mut clauses = Vec::new();
+            for (index, clause) in rule.clauses.iter().flatten().enumerate() {
+                let location = Location::Clause {
+                    rule: rule_index,
+                    clause: index,
+                };
+                let (attribute, op) = match (&clause.attribute, &clause.op) {
+                    (Some(attribute), Some(op)) => (attribute, op),
+                    _ => {
+                        validation.report(location, Problem::InvalidClause);
+                        continue;
+                    }
+                };
+                let operator = Operator::new(op, clause.values.as_deref().unwrap_or_default());
+                if let Operator::Unknown = operator {
+                    validation.report(location, Problem::UnsupportedOperator(op.clone()));
+                }
+                clauses.push(CompiledClause {
+                    attribute: attribute.clone(),
+                    operator,
+                    negate: clause.negate.unwrap_or(false),
+                });
+            }
+            rules.push(CompiledRule {
+                clauses,
+                clause_error: validation.first_error(start),
+                error,
+            });
+        }
+
+        let location = Location::Fallthrough;
+        let start = validation.diagnostics.len();
+        match (flag.fallthrough.variation, &flag.fallthrough.rollout) {
+            (Some(variation), _) => validation.check_index(location, variation),
+            (None, Some(rollout)) => validation.check_rollout(location, rollout),
+            (None, None) => validation.report(location, Problem::EmptyFallthrough),
+        }
+        let fallthrough_error = validation.first_error(start);
+
+        Self {
+            flag,
+            targets,
+            rules,
+            fallthrough_error,
+            diagnostics: validation.diagnostics,
+        }
+    }
+
+    /// Problems found in the flag data
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Variation a user key is targeted with
+    ///
+    /// Fails if the target listing the user is malformed.
+    pub(crate) fn target(&self, user_key: &str) -> Option<Result<usize, Error>> {
+        self.targets.get(user_key).cloned()
+    }
+
+    /// Compiled rules, in the same order as the rules of the flag
+    pub(crate) fn rules(&self) -> &[CompiledRule] {
+        &self.rules
+    }
+
+    /// Problem of the fallthrough, failing evaluations reaching it
+    pub(crate) fn fallthrough_error(&self) -> Option<&Error> {
+        self.fallthrough_error.as_ref()
+    }
+
+    /// The flag data without the lookup structures
+    pub fn into_inner(self) -> FeatureFlagState {
+        self.flag
+    }
+}
+
+impl Deref for CompiledFlag {
+    type Target = FeatureFlagState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.flag
+    }
+}
+
+/// Serializes the flag data only
+impl Serialize for CompiledFlag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.flag.serialize(serializer)
+    }
+}
+
+impl From<FeatureFlagState> for CompiledFlag {
+    fn from(flag: FeatureFlagState) -> Self {
+        Self::new(flag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompiledFlag;
+    use crate::{evaluator::Error, test_utils::FlagBuilder};
+
+    #[test]
+    fn targets() {
+        let flag = FlagBuilder::default()
+            .add_target(0, "a")
+            .add_target(1, "b")
+            .add_target(1, "a")
+            .into_inner();
+        let flag = CompiledFlag::new(flag);
+        assert_eq!(Some(Ok(0)), flag.target("a"));
+        assert_eq!(Some(Ok(1)), flag.target("b"));
+        assert_eq!(None, flag.target("c"));
+        assert!(flag.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn invalid_index() {
+        let flag = FlagBuilder::default()
+            .add_target(5, "a")
+            .add_target(1, "b")
+            .into_inner();
+        let flag = CompiledFlag::new(flag);
+        // only users of the broken target are affected
+        assert_eq!(Some(Err(Error::IndexOutOfRange)), flag.target("a"));
+        assert_eq!(Some(Ok(1)), flag.target("b"));
+        assert_eq!(None, flag.fallthrough_error());
+
+        let flag = FlagBuilder::default()
+            .with_fallthrough_variation(2)
+            .into_inner();
+        let flag = CompiledFlag::new(flag);
+        assert_eq!(Some(&Error::IndexOutOfRange), flag.fallthrough_error());
+    }
+}
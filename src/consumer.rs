@@ -1,8 +1,14 @@
-use crate::{message::Message, source::Source};
-use futures::{future::BoxFuture, Future, FutureExt, StreamExt};
-use std::{error::Error as StdError, fmt, sync::Arc};
-use tokio::{sync::watch, task};
-use tracing::warn;
+use crate::{diagnostics::Diagnostics, events::now_millis, message::Message, source::Source};
+use futures::{
+    future::{self, BoxFuture},
+    Future, FutureExt, StreamExt,
+};
+use std::{error::Error as StdError, fmt, sync::Arc, time::Instant};
+use tokio::{
+    sync::{oneshot, watch},
+    task::{self, JoinHandle},
+};
+use tracing::{debug, error, warn};
 
 #[derive(Clone, Debug, thiserror::Error)]
 pub enum ReadError<E>
@@ -15,10 +21,60 @@ where
     #[error("Starting stream failed 4 times in a row")]
     RetryFailed,
 
+    #[error("Stream rejected the connection: {0}")]
+    Rejected(String),
+
     #[error(transparent)]
     Inner(#[from] E),
 }
 
+/// Future resolving once a [Consumer] got the init data
+pub type InitFuture<E> = BoxFuture<'static, Result<(), ReadError<E>>>;
+
+/// Handle to the background task started by [Consumer::read_from_with_handle]
+///
+/// Dropping the handle aborts the task. Use [close](Self::close)
+/// to stop reading gracefully.
+pub struct ReadHandle {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ReadHandle {
+    /// Handle to a task stopping once `shutdown_tx` fires
+    pub(crate) fn new(shutdown_tx: oneshot::Sender<()>, task: JoinHandle<()>) -> Self {
+        Self {
+            shutdown_tx: Some(shutdown_tx),
+            task: Some(task),
+        }
+    }
+
+    /// Stop reading from the source and wait for the task to finish
+    ///
+    /// A message that is currently being consumed is processed first.
+    pub async fn close(mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    /// Let the task run on its own, it can't be stopped anymore
+    fn detach(mut self) {
+        self.task.take();
+    }
+}
+
+impl Drop for ReadHandle {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
 /// Represents the state of a [Consumer]
 /// after consuming a message
 pub enum InitState {
@@ -50,11 +106,43 @@ pub trait Consumer<S> {
     /// Waits until the consumer got the init data (transitioned to InitState::Done).
     ///
     /// When not interested in readiness, just drop the returned future. This has no
-    /// bad consequences.
-    fn read_from(
+    /// bad consequences. The task can't be stopped, use
+    /// [read_from_with_handle](Self::read_from_with_handle) for that.
+    fn read_from(self: Arc<Self>, source: S) -> InitFuture<Self::Error>
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: fmt::Debug + StdError + Clone + Sync + Send,
+        S: Source + Send + 'static,
+        S::Stream: Unpin + Send,
+        S::Error: fmt::Display + Send,
+    {
+        let (handle, init) = read(self, source, None);
+        handle.detach();
+        init
+    }
+
+    /// Same as [read_from](Self::read_from), but returns a handle to the task
+    /// along with the future waiting for the init data.
+    ///
+    /// The task keeps running as long as the returned handle is alive.
+    fn read_from_with_handle(self: Arc<Self>, source: S) -> (ReadHandle, InitFuture<Self::Error>)
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: fmt::Debug + StdError + Clone + Sync + Send,
+        S: Source + Send + 'static,
+        S::Stream: Unpin + Send,
+        S::Error: fmt::Display + Send,
+    {
+        read(self, source, None)
+    }
+
+    /// Same as [read_from_with_handle](Self::read_from_with_handle), but records
+    /// every attempt of connecting to the stream for diagnostic events.
+    fn read_from_with_diagnostics(
         self: Arc<Self>,
         source: S,
-    ) -> BoxFuture<'static, Result<(), ReadError<Self::Error>>>
+        diagnostics: Arc<Diagnostics>,
+    ) -> (ReadHandle, InitFuture<Self::Error>)
     where
         Self: Send + Sync + 'static,
         Self::Error: fmt::Debug + StdError + Clone + Sync + Send,
@@ -62,55 +150,126 @@ pub trait Consumer<S> {
         S::Stream: Unpin + Send,
         S::Error: fmt::Display + Send,
     {
-        let (init_tx, mut init_rx) =
-            watch::channel::<Option<Result<(), ReadError<Self::Error>>>>(None);
-
-        task::spawn(async move {
-            let mut stream = source.stream();
-            let mut failures = 0;
-            while failures < 4 {
-                let msg = match stream.next().await {
-                    Some(Ok(msg)) => msg,
-                    Some(Err(error)) => {
-                        failures += 1;
-                        warn!(%error, "failed processing event, restarting stream");
-                        // TODO: consider exponential backoff
-                        // retry stream (usually reopens the connection)
-                        stream = source.stream();
-                        continue;
+        read(self, source, Some(diagnostics))
+    }
+}
+
+/// Attempt of connecting to the stream, finished by the first
+/// message or error
+struct StreamAttempt {
+    timestamp: u64,
+    started: Instant,
+}
+
+impl StreamAttempt {
+    fn start() -> Self {
+        Self {
+            timestamp: now_millis(),
+            started: Instant::now(),
+        }
+    }
+
+    fn finish(self, diagnostics: Option<&Arc<Diagnostics>>, failed: bool) {
+        if let Some(diagnostics) = diagnostics {
+            diagnostics.record_stream_init(self.timestamp, self.started.elapsed(), failed);
+        }
+    }
+}
+
+/// Implementation of [Consumer::read_from_with_handle]
+fn read<C, S>(
+    consumer: Arc<C>,
+    source: S,
+    diagnostics: Option<Arc<Diagnostics>>,
+) -> (ReadHandle, InitFuture<C::Error>)
+where
+    C: Consumer<S> + Send + Sync + ?Sized + 'static,
+    C::Error: fmt::Debug + StdError + Clone + Sync + Send,
+    S: Source + Send + 'static,
+    S::Stream: Unpin + Send,
+    S::Error: fmt::Display + Send,
+{
+    let (init_tx, mut init_rx) = watch::channel::<Option<Result<(), ReadError<C::Error>>>>(None);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let task = task::spawn(async move {
+        // a dropped sender only means the task was detached
+        let shutdown = async move {
+            if shutdown_rx.await.is_err() {
+                future::pending::<()>().await;
+            }
+        };
+        tokio::pin!(shutdown);
+        let mut stream = source.stream();
+        let mut attempt = Some(StreamAttempt::start());
+        let mut failures = 0;
+        while failures < 4 {
+            let next = tokio::select! {
+                next = stream.next() => next,
+                _ = &mut shutdown => {
+                    debug!("stopped reading from source");
+                    return;
+                }
+            };
+            let msg = match next {
+                Some(Ok(msg)) => {
+                    if let Some(attempt) = attempt.take() {
+                        attempt.finish(diagnostics.as_ref(), false);
                     }
-                    None => return,
-                };
-                // reset failure counter after single successful read
-                failures = 0;
-
-                match self.consume(msg).await {
-                    Err(e) => {
-                        let _ = init_tx.send(Some(Err(e.into())));
+                    msg
+                }
+                Some(Err(error)) => {
+                    if let Some(attempt) = attempt.take() {
+                        attempt.finish(diagnostics.as_ref(), true);
                     }
-                    Ok(InitState::Done) => {
-                        let _ = init_tx.send(Some(Ok(())));
+                    if source.is_unrecoverable(&error) {
+                        error!(%error, "stream rejected the connection, giving up");
+                        let _ = init_tx.send(Some(Err(ReadError::Rejected(error.to_string()))));
+                        return;
                     }
-                    Ok(InitState::Pending) => {}
-                };
-            }
+                    failures += 1;
+                    warn!(%error, "failed processing event, restarting stream");
+                    // TODO: consider exponential backoff
+                    // retry stream (usually reopens the connection)
+                    stream = source.stream();
+                    attempt = Some(StreamAttempt::start());
+                    continue;
+                }
+                None => return,
+            };
+            // reset failure counter after single successful read
+            failures = 0;
 
-            // Exited loop after too many failures
-            let _ = init_tx.send(Some(Err(ReadError::RetryFailed)));
-        });
-
-        // future to wait for readiness
-        async move {
-            if init_rx.borrow().is_none() {
-                init_rx
-                    .changed()
-                    .await
-                    .map_err(|_| ReadError::TaskDropped)?;
-            }
-            // safe to unwrap: if it's still None at this point, it's a bug
-            let res = init_rx.borrow().as_ref().cloned().unwrap();
-            res
+            match consumer.consume(msg).await {
+                Err(e) => {
+                    let _ = init_tx.send(Some(Err(e.into())));
+                }
+                Ok(InitState::Done) => {
+                    let _ = init_tx.send(Some(Ok(())));
+                }
+                Ok(InitState::Pending) => {}
+            };
         }
-        .boxed()
+
+        // Exited loop after too many failures
+        let _ = init_tx.send(Some(Err(ReadError::RetryFailed)));
+    });
+
+    let handle = ReadHandle::new(shutdown_tx, task);
+
+    // future to wait for readiness
+    let init = async move {
+        if init_rx.borrow().is_none() {
+            init_rx
+                .changed()
+                .await
+                .map_err(|_| ReadError::TaskDropped)?;
+        }
+        // safe to unwrap: if it's still None at this point, it's a bug
+        let res = init_rx.borrow().as_ref().cloned().unwrap();
+        res
     }
+    .boxed();
+
+    (handle, init)
 }
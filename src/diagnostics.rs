@@ -0,0 +1,247 @@
+//! Diagnostic events reporting the health of the SDK to LaunchDarkly
+//!
+//! https://docs.launchdarkly.com/sdk/concepts/events#diagnostic-events
+
+use crate::events::{now_millis, Config};
+use serde::Serialize;
+use std::{mem, sync::Mutex, time::Duration};
+use uuid::Uuid;
+
+/// Identifies the diagnostic events of a single SDK instance
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticId {
+    pub diagnostic_id: String,
+    /// Last 6 characters of the SDK key
+    pub sdk_key_suffix: String,
+}
+
+/// Event sent to the diagnostic endpoint
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind")]
+pub enum DiagnosticEvent {
+    /// Sent once on startup
+    #[serde(rename = "diagnostic-init")]
+    Init(DiagnosticInit),
+    /// Sent periodically
+    #[serde(rename = "diagnostic")]
+    Stats(DiagnosticStats),
+}
+
+/// Describes the SDK, its configuration and the platform it runs on
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticInit {
+    pub id: DiagnosticId,
+    pub creation_date: u64,
+    pub sdk: serde_json::Value,
+    pub configuration: serde_json::Value,
+    pub platform: serde_json::Value,
+}
+
+/// Statistics collected since the previous diagnostic event
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticStats {
+    pub id: DiagnosticId,
+    pub creation_date: u64,
+    pub data_since_date: u64,
+    pub dropped_events: u64,
+    pub deduplicated_users: u64,
+    pub events_in_last_batch: u64,
+    /// Events waiting to be sent when the statistics were taken
+    pub events_in_queue: u64,
+    pub stream_inits: Vec<StreamInit>,
+}
+
+/// Single attempt of connecting to the stream
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamInit {
+    /// Start of the attempt, in milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub failed: bool,
+    pub duration_millis: u64,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    data_since_date: u64,
+    dropped_events: u64,
+    deduplicated_users: u64,
+    events_in_last_batch: u64,
+    stream_inits: Vec<StreamInit>,
+}
+
+/// Collects the data for diagnostic events
+///
+/// Shared between the [EventProcessor](crate::events::EventProcessor)
+/// sending the events and [Consumer::read_from_with_diagnostics](crate::consumer::Consumer::read_from_with_diagnostics)
+/// recording stream connections.
+#[derive(Debug)]
+pub struct Diagnostics {
+    id: DiagnosticId,
+    stats: Mutex<Stats>,
+}
+
+impl Diagnostics {
+    /// Create a recorder for an SDK instance using an SDK key
+    pub fn new(sdk_key: &str) -> Self {
+        let suffix_start = sdk_key
+            .char_indices()
+            .rev()
+            .nth(5)
+            .map_or(0, |(idx, _)| idx);
+        Self {
+            id: DiagnosticId {
+                diagnostic_id: Uuid::new_v4().to_string(),
+                sdk_key_suffix: sdk_key[suffix_start..].into(),
+            },
+            stats: Mutex::new(Stats {
+                data_since_date: now_millis(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Record an attempt of connecting to the stream
+    ///
+    /// `timestamp` is the start of the attempt in milliseconds since the unix epoch.
+    pub fn record_stream_init(&self, timestamp: u64, duration: Duration, failed: bool) {
+        self.stats.lock().unwrap().stream_inits.push(StreamInit {
+            timestamp,
+            failed,
+            duration_millis: duration.as_millis() as u64,
+        });
+    }
+
+    pub(crate) fn record_dropped_event(&self) {
+        self.stats.lock().unwrap().dropped_events += 1;
+    }
+
+    pub(crate) fn record_deduplicated_user(&self) {
+        self.stats.lock().unwrap().deduplicated_users += 1;
+    }
+
+    pub(crate) fn record_batch(&self, num_events: usize) {
+        self.stats.lock().unwrap().events_in_last_batch = num_events as u64;
+    }
+
+    /// Event describing the SDK and its configuration
+    pub(crate) fn init_event(&self, config: &Config) -> DiagnosticEvent {
+        DiagnosticEvent::Init(DiagnosticInit {
+            id: self.id.clone(),
+            creation_date: now_millis(),
+            sdk: serde_json::json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            }),
+            configuration: serde_json::json!({
+                "eventsCapacity": config.capacity,
+                "eventsFlushIntervalMillis": config.flush_interval.as_millis() as u64,
+                "allAttributesPrivate": config.all_attributes_private,
+                "userKeysCapacity": config.user_keys_capacity,
+                "userKeysFlushIntervalMillis": config.user_keys_flush_interval.as_millis() as u64,
+                "inlineUsersInEvents": config.inline_users_in_events,
+                "diagnosticRecordingIntervalMillis":
+                    config.diagnostic_recording_interval.as_millis() as u64,
+            }),
+            platform: serde_json::json!({
+                "name": "Rust",
+                "osName": std::env::consts::OS,
+                "osArch": std::env::consts::ARCH,
+            }),
+        })
+    }
+
+    /// Event with the statistics since the previous call
+    ///
+    /// `events_in_queue` is the number of events waiting to be sent.
+    /// Resets all counters, but keeps the size of the last flushed batch.
+    pub(crate) fn stats_event(&self, events_in_queue: usize) -> DiagnosticEvent {
+        let now = now_millis();
+        let mut stats = self.stats.lock().unwrap();
+        let events_in_last_batch = stats.events_in_last_batch;
+        let previous = mem::replace(
+            &mut *stats,
+            Stats {
+                data_since_date: now,
+                events_in_last_batch,
+                ..Default::default()
+            },
+        );
+        DiagnosticEvent::Stats(DiagnosticStats {
+            id: self.id.clone(),
+            creation_date: now,
+            data_since_date: previous.data_since_date,
+            dropped_events: previous.dropped_events,
+            deduplicated_users: previous.deduplicated_users,
+            events_in_last_batch: previous.events_in_last_batch,
+            events_in_queue: events_in_queue as u64,
+            stream_inits: previous.stream_inits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiagnosticEvent, Diagnostics, StreamInit};
+    use crate::events::Config;
+    use std::time::Duration;
+
+    #[test]
+    fn key_suffix() {
+        let diagnostics = Diagnostics::new("sdk-1234567890ab");
+        assert_eq!("7890ab", diagnostics.id.sdk_key_suffix);
+        let diagnostics = Diagnostics::new("abc");
+        assert_eq!("abc", diagnostics.id.sdk_key_suffix);
+    }
+
+    #[test]
+    fn init_event() {
+        let diagnostics = Diagnostics::new("sdk-key");
+        let event = diagnostics.init_event(&Config::default());
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!("diagnostic-init", json["kind"]);
+        assert_eq!("Rust", json["platform"]["name"]);
+        assert_eq!(10_000, json["configuration"]["eventsCapacity"]);
+        assert_eq!(diagnostics.id.diagnostic_id, json["id"]["diagnosticId"]);
+    }
+
+    #[test]
+    fn stats_reset() {
+        let diagnostics = Diagnostics::new("sdk-key");
+        diagnostics.record_stream_init(1000, Duration::from_millis(50), true);
+        diagnostics.record_dropped_event();
+        diagnostics.record_deduplicated_user();
+        diagnostics.record_deduplicated_user();
+        diagnostics.record_batch(3);
+
+        let stats = match diagnostics.stats_event(2) {
+            DiagnosticEvent::Stats(stats) => stats,
+            _ => panic!("expected stats event"),
+        };
+        assert_eq!(1, stats.dropped_events);
+        assert_eq!(2, stats.deduplicated_users);
+        assert_eq!(3, stats.events_in_last_batch);
+        assert_eq!(2, stats.events_in_queue);
+        assert_eq!(
+            vec![StreamInit {
+                timestamp: 1000,
+                failed: true,
+                duration_millis: 50
+            }],
+            stats.stream_inits
+        );
+
+        let next = match diagnostics.stats_event(0) {
+            DiagnosticEvent::Stats(stats) => stats,
+            _ => panic!("expected stats event"),
+        };
+        assert_eq!(0, next.dropped_events);
+        // no flush since the previous event
+        assert_eq!(3, next.events_in_last_batch);
+        assert!(next.stream_inits.is_empty());
+        assert_eq!(stats.creation_date, next.data_since_date);
+    }
+}
@@ -1,15 +1,24 @@
 use crate::{
+    compiled::{CompiledClause, CompiledFlag},
+    hooks::{EvaluationContext, Hook, Hooks},
     models::{fallthrough::Fallthrough, rollout::Rollout, FeatureFlagState},
-    store::Store,
+    store::{Store, StoreSnapshot},
 };
 use hex::ToHex;
+use serde::{Deserialize, Deserializer, Serialize};
 use sha1::{Digest, Sha1};
-use std::ops::Div;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::Div,
+    sync::Arc,
+};
 use tracing::warn;
 
 const BUCKET_DIVIDER: f64 = 0xFFFFFFFFFFFFFFFu64 as f64;
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
 pub enum Error {
     #[error("Requested flag was not found")]
     FlagNotFound,
@@ -29,8 +38,11 @@ pub enum Error {
     #[error("Malformed variations in rollout")]
     InvalidRollout,
 
-    #[error("Evaluation of rules is not supported right now")]
-    UnsupportedRules,
+    #[error("Rule was invalid")]
+    InvalidRule,
+
+    #[error("Clause operator is not supported")]
+    UnsupportedOperator,
 
     #[error("Fallthrough is expected to either have a fixed variation or a rollout")]
     EmptyFallthrough,
@@ -42,44 +54,284 @@ pub enum Error {
     InvalidVariationType,
 }
 
+impl Error {
+    /// Categorize the error for reporting
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::FlagNotFound => ErrorKind::FlagNotFound,
+            Self::InvalidVariationType => ErrorKind::WrongType,
+            Self::FlagOff | Self::PrerequisiteFailed | Self::UnsupportedOperator => {
+                ErrorKind::Exception
+            }
+            Self::InvalidPrerequisite
+            | Self::InvalidTarget
+            | Self::InvalidRule
+            | Self::InvalidRollout
+            | Self::EmptyFallthrough
+            | Self::IndexOutOfRange => ErrorKind::MalformedFlag,
+        }
+    }
+}
+
+/// Optional user attributes with a predefined meaning
+///
+/// All other attributes are considered custom.
+pub const BUILT_IN_ATTRIBUTES: &[&str] = &[
+    "secondary",
+    "ip",
+    "country",
+    "email",
+    "firstName",
+    "lastName",
+    "avatar",
+    "name",
+    "anonymous",
+];
+
 /// Represents a user
 ///
-/// Has a single key right now
-#[derive(Debug)]
+/// Identified by a key, can carry additional attributes.
+#[derive(Debug, Clone, Default)]
 pub struct User<'a> {
-    key: &'a str,
-    // todo: Support additional attributes (key-value)
+    key: Cow<'a, str>,
+    attributes: HashMap<String, serde_json::Value>,
+    private_attribute_names: HashSet<String>,
 }
 
 impl<'a> User<'a> {
     /// Create a user based on a key
-    pub fn new(key: &'a str) -> Self {
-        Self { key }
+    pub fn new<K: Into<Cow<'a, str>>>(key: K) -> Self {
+        Self {
+            key: key.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set an attribute
+    ///
+    /// Names of [built-in attributes](BUILT_IN_ATTRIBUTES) are used as is,
+    /// everything else is a custom attribute.
+    pub fn with_attribute<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<serde_json::Value>,
+    {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+
+    /// Set an attribute that must not be sent to LaunchDarkly in events
+    ///
+    /// It can still be used for evaluating flags.
+    pub fn with_private_attribute<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<serde_json::Value>,
+    {
+        let name = name.into();
+        self.private_attribute_names.insert(name.clone());
+        self.with_attribute(name, value)
+    }
+
+    /// Unique key of the user
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Value of an attribute, including the key
+    pub fn attribute(&self, name: &str) -> Option<Cow<'_, serde_json::Value>> {
+        if name == "key" {
+            return Some(Cow::Owned(self.key().into()));
+        }
+        self.attributes.get(name).map(Cow::Borrowed)
+    }
+
+    /// All attributes except the key
+    pub fn attributes(&self) -> impl Iterator<Item = (&str, &serde_json::Value)> {
+        self.attributes.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// Whether an attribute was marked as private for this user
+    pub fn is_private(&self, name: &str) -> bool {
+        self.private_attribute_names.contains(name)
+    }
+}
+
+/// User in the JSON format shared by all SDKs
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserData {
+    key: String,
+    #[serde(default)]
+    custom: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    private_attribute_names: HashSet<String>,
+    /// built-in attributes
+    #[serde(flatten)]
+    attributes: HashMap<String, serde_json::Value>,
+}
+
+/// Reads the JSON format shared by all SDKs,
+/// with custom attributes nested in `custom`
+impl<'de, 'a> Deserialize<'de> for User<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = UserData::deserialize(deserializer)?;
+        let attributes = data
+            .attributes
+            .into_iter()
+            .chain(data.custom)
+            .filter(|(_, value)| !value.is_null())
+            .collect();
+        Ok(Self {
+            key: data.key.into(),
+            attributes,
+            private_attribute_names: data.private_attribute_names,
+        })
     }
 }
 
+/// Result of an evaluation
+///
+/// Contains the variation value and additional info
+/// about how it was determined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detail {
+    /// Value of the variation
+    pub value: serde_json::Value,
+    /// Index of the variation in the flag
+    pub variation_index: usize,
+    /// Why the variation was chosen
+    pub reason: Reason,
+}
+
+/// Explains how the variation of an evaluation was determined
+///
+/// Serializes to the format used by all LaunchDarkly SDKs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Reason {
+    /// The flag is off
+    Off,
+    /// No target or rule matched
+    #[serde(rename_all = "camelCase")]
+    Fallthrough {
+        #[serde(skip_serializing_if = "is_false")]
+        in_experiment: bool,
+    },
+    /// The user is targeted individually
+    TargetMatch,
+    /// The user matched a rule
+    #[serde(rename_all = "camelCase")]
+    RuleMatch {
+        rule_index: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rule_id: Option<String>,
+        #[serde(skip_serializing_if = "is_false")]
+        in_experiment: bool,
+    },
+    /// A prerequisite flag did not return the expected variation
+    #[serde(rename_all = "camelCase")]
+    PrerequisiteFailed { prerequisite_key: String },
+    /// The flag could not be evaluated
+    #[serde(rename_all = "camelCase")]
+    Error { error_kind: ErrorKind },
+}
+
+impl Reason {
+    /// Whether the evaluation has to be tracked with full fidelity
+    ///
+    /// This is the case for users in an experiment, for rules with `trackEvents`
+    /// and for the fallthrough with `trackEventsFallthrough`.
+    pub fn is_experiment(&self, flag: &FeatureFlagState) -> bool {
+        match self {
+            Self::Fallthrough { in_experiment } => *in_experiment || flag.track_events_fallthrough,
+            Self::RuleMatch {
+                rule_index,
+                in_experiment,
+                ..
+            } => {
+                *in_experiment
+                    || flag
+                        .rules
+                        .get(*rule_index)
+                        .and_then(|rule| rule.track_events)
+                        .unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+/// Category of an [Error] as reported in a [Reason]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorKind {
+    ClientNotReady,
+    FlagNotFound,
+    MalformedFlag,
+    WrongType,
+    Exception,
+}
+
 /// Used to evaluate flags by reading from a [Store]
 /// and running the [flag algorithm](https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules).
 pub struct Evaluator<S> {
     store: S,
+    hooks: Hooks,
 }
 
 /// Helper for a single evaluation
 ///
 /// Contains the actual evaluation implementation
 pub struct Evaluation<'a, 'u, S> {
-    flag: &'a FeatureFlagState,
+    flag: &'a CompiledFlag,
     user: &'a User<'u>,
     store: &'a S,
+    /// Results shared with the other evaluations of a [Batch]
+    variations: Option<&'a Variations>,
+    /// Flags whose prerequisites are being evaluated
+    parents: Option<&'a Parents<'a>>,
+}
+
+/// Chain of flags leading to a prerequisite, used to detect cycles
+struct Parents<'a> {
+    key: &'a str,
+    next: Option<&'a Parents<'a>>,
 }
 
+impl Parents<'_> {
+    fn contains(&self, key: &str) -> bool {
+        let mut parents = Some(self);
+        while let Some(parent) = parents {
+            if parent.key == key {
+                return true;
+            }
+            parents = parent.next;
+        }
+        false
+    }
+}
+
+/// Variation and reason of the flags evaluated in a [Batch], by key
+type Variations = RefCell<HashMap<String, Result<(usize, Reason), Error>>>;
+
 impl<'a, 'u, S: Store> Evaluation<'a, 'u, S> {
     /// Create an evaluation from a store, a flag, a user
     ///
     /// The store is required to fetch more flags in the
     /// prerequisites step.
-    pub fn new(store: &'a S, flag: &'a FeatureFlagState, user: &'a User<'u>) -> Self {
-        Self { flag, user, store }
+    pub fn new(store: &'a S, flag: &'a CompiledFlag, user: &'a User<'u>) -> Self {
+        Self {
+            flag,
+            user,
+            store,
+            variations: None,
+            parents: None,
+        }
     }
 
     /// Runs the evaluation algorithm and returns the correct
@@ -88,55 +340,103 @@ impl<'a, 'u, S: Store> Evaluation<'a, 'u, S> {
     /// Returns a [json `Value` enum](serde_json::Value) which should
     /// be tried to cast into the desired type
     pub fn run(&self) -> Result<serde_json::Value, Error> {
-        let index = self.index()?;
+        self.detail().map(|detail| detail.value)
+    }
+
+    /// Runs the evaluation algorithm and returns the variation
+    /// value along with its index
+    pub fn detail(&self) -> Result<Detail, Error> {
+        let (variation_index, reason) = self.cached_variation()?;
 
-        let variation = self
+        let value = self
             .flag
             .variations
-            .get(index)
+            .get(variation_index)
             .ok_or(Error::IndexOutOfRange)?
             .clone();
-        Ok(variation)
+        Ok(Detail {
+            value,
+            variation_index,
+            reason,
+        })
     }
 
     /// Find the variation index for this evaluation
     ///
-    /// Runs the evaluation algortihm described here:
-    /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules
-    ///
     /// The returned number can be used as an index into the variations
     /// of a flag.
-    //
-    // todo: Return a reason with the result
     fn index(&self) -> Result<usize, Error> {
+        self.cached_variation().map(|(index, _)| index)
+    }
+
+    /// Same as [variation](Self::variation), but reuses the result
+    /// of an earlier evaluation of the flag in the same [Batch]
+    fn cached_variation(&self) -> Result<(usize, Reason), Error> {
+        let variations = match self.variations {
+            Some(variations) => variations,
+            None => return self.variation(),
+        };
+        if let Some(result) = variations.borrow().get(&self.flag.key) {
+            return result.clone();
+        }
+        let result = self.variation();
+        variations
+            .borrow_mut()
+            .insert(self.flag.key.clone(), result.clone());
+        result
+    }
+
+    /// Find the variation index for this evaluation along with
+    /// the reason for choosing it
+    ///
+    /// Runs the evaluation algortihm described here:
+    /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules
+    fn variation(&self) -> Result<(usize, Reason), Error> {
         // Preliminary checks
         // https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#preliminary-checks
         if self.user.key.is_empty() {
             warn!("User key is empty");
         }
         if !self.flag.on {
-            return Ok(self.flag.off_variation);
+            return Ok((self.flag.off_variation, Reason::Off));
         }
 
-        if self.prerequisites().is_err() {
-            return Ok(self.flag.off_variation);
+        if let Some(prerequisite_key) = self.prerequisites()? {
+            let reason = Reason::PrerequisiteFailed {
+                prerequisite_key: prerequisite_key.into(),
+            };
+            return Ok((self.flag.off_variation, reason));
         }
 
-        if let Some(target_variation) = self.targets()? {
-            return Ok(target_variation as usize);
+        // malformed parts of the flag are detected when compiling it,
+        // they only fail the evaluation once it gets to them
+        if let Some(target_variation) = self.flag.target(self.user.key()) {
+            return Ok((target_variation?, Reason::TargetMatch));
         }
 
-        if let Some(rule_variation) = self.rules()? {
-            return Ok(rule_variation as usize);
+        if let Some((rule_variation, reason)) = self.rules()? {
+            return Ok((rule_variation as usize, reason));
         }
 
-        self.fallthrough().map(|v| v as usize)
+        let (fallthrough_variation, in_experiment) = self.fallthrough()?;
+        Ok((
+            fallthrough_variation as usize,
+            Reason::Fallthrough { in_experiment },
+        ))
     }
 
     /// Checks prerequesite flags
     ///
     /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#prerequisite-checks
-    fn prerequisites(&self) -> Result<(), Error> {
+    ///
+    /// Returns the key of the first prerequisite that is not met.
+    /// Fails when a prerequisite depends on the flag itself, directly or
+    /// through other prerequisites, or when its own prerequisites are malformed.
+    fn prerequisites(&self) -> Result<Option<&'a str>, Error> {
+        let parents = Parents {
+            key: &self.flag.key,
+            next: self.parents,
+        };
         for prereq in &self.flag.prerequisites {
             // get flag name and expected variation index
             let (key, expected) = prereq
@@ -144,67 +444,100 @@ impl<'a, 'u, S: Store> Evaluation<'a, 'u, S> {
                 .as_ref()
                 .and_then(|k| prereq.variation.map(|v| (k, v)))
                 .ok_or(Error::InvalidPrerequisite)?;
-            // retrieve flag
-            let flag = self.store.flag(key).ok_or(Error::FlagNotFound)?;
-            if !flag.on {
-                return Err(Error::FlagOff);
+            if parents.contains(key) {
+                warn!(flag = %self.flag.key, prerequisite = %key, "prerequisite cycle");
+                return Err(Error::InvalidPrerequisite);
             }
+            // retrieve flag, missing or disabled prerequisites are not met
+            let flag = match self.store.flag(key) {
+                Some(flag) if flag.on => flag,
+                _ => return Ok(Some(key)),
+            };
             // compute variation index for the flag
-            let index = Evaluation::new(self.store, &flag, self.user).index()? as i64;
-            if index != expected {
+            let prereq = Evaluation {
+                flag: &flag,
+                parents: Some(&parents),
+                ..*self
+            };
+            match prereq.index() {
+                Ok(index) if index as i64 == expected => {}
+                Err(Error::InvalidPrerequisite) => return Err(Error::InvalidPrerequisite),
                 // short-circuit once the first value differs
-                return Err(Error::PrerequisiteFailed);
+                _ => return Ok(Some(key)),
             }
         }
-        Ok(())
+        Ok(None)
     }
 
-    /// Checks individual target matches
+    /// Checks rule matches
     ///
-    /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#individual-targeting-checks
-    fn targets(&self) -> Result<Option<i64>, Error> {
-        for target in &self.flag.targets {
-            // look at a target variation
-            let (values, variation) = target
-                .values
-                .as_ref()
-                .and_then(|vals| target.variation.map(|v| (vals, v)))
-                .ok_or(Error::InvalidTarget)?;
-            for value in values {
-                if value == self.user.key {
-                    // return variation if matches user
-                    return Ok(Some(variation));
-                }
+    /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#targeting-rule-checks
+    ///
+    /// Returns the variation of the first rule where all clauses match.
+    fn rules(&self) -> Result<Option<(i64, Reason)>, Error> {
+        let rules = self.flag.rules.iter().zip(self.flag.rules());
+        for (rule_index, (rule, compiled)) in rules.enumerate() {
+            if let Some(error) = &compiled.clause_error {
+                return Err(error.clone());
             }
+            if !self.rule_matches(&compiled.clauses) {
+                continue;
+            }
+            if let Some(error) = &compiled.error {
+                return Err(error.clone());
+            }
+            let (variation, in_experiment) = match (rule.variation, &rule.rollout) {
+                (Some(variation), _) => (variation, false),
+                (None, Some(rollout)) => self.rollout(rollout)?,
+                (None, None) => return Err(Error::InvalidRule),
+            };
+            let reason = Reason::RuleMatch {
+                rule_index,
+                rule_id: rule.id.clone(),
+                in_experiment,
+            };
+            return Ok(Some((variation, reason)));
         }
         Ok(None)
     }
 
-    /// Checks rule matches
-    ///
-    /// UNSUPPORTED right now.
-    /// Will return an error if the flag has rules.
+    /// Whether all clauses of a rule match the user
+    fn rule_matches(&self, clauses: &[CompiledClause]) -> bool {
+        clauses.iter().all(|clause| self.clause_matches(clause))
+    }
+
+    /// Whether a user attribute matches any of the clause values
     ///
-    /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#targeting-rule-checks
-    fn rules(&self) -> Result<Option<i64>, Error> {
-        // TODO: Support rule matching
-        if !self.flag.rules.is_empty() {
-            return Err(Error::UnsupportedRules);
-        }
-        Ok(None)
+    /// For attributes holding an array, any of the elements can match.
+    /// Clauses never match if the user does not have the attribute,
+    /// regardless of negation.
+    fn clause_matches(&self, clause: &CompiledClause) -> bool {
+        let user_value = match self.user.attribute(&clause.attribute) {
+            Some(value) => value,
+            None => return false,
+        };
+        let matched = match user_value.as_ref() {
+            serde_json::Value::Array(values) => values.iter().any(|v| clause.operator.matches(v)),
+            value => clause.operator.matches(value),
+        };
+        matched != clause.negate
     }
 
     /// Determine falltrough variation
     ///
     /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#fallthrough
     ///
-    /// Fails if neither single variation nor rollout present
-    fn fallthrough(&self) -> Result<i64, Error> {
+    /// Fails if neither single variation nor rollout present.
+    /// Also returns whether the user is part of an experiment.
+    fn fallthrough(&self) -> Result<(i64, bool), Error> {
+        if let Some(error) = self.flag.fallthrough_error() {
+            return Err(error.clone());
+        }
         let Fallthrough { variation, rollout } = &self.flag.fallthrough;
 
         // simple route: single fallthrough variation
         if let Some(variation) = variation {
-            return Ok(*variation);
+            return Ok((*variation, false));
         }
 
         // advanced: percentage-based rollout
@@ -217,16 +550,20 @@ impl<'a, 'u, S: Store> Evaluation<'a, 'u, S> {
     /// Each rollout segment has a relative value.
     /// With correct data they add up to 100%.
     ///
+    /// For rollouts of kind `experiment` this also returns whether the
+    /// user is part of the experiment, i.e. not bucketed into an untracked variation.
+    ///
     /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#rollouts
-    fn rollout(&self, rollout: &Rollout) -> Result<i64, Error> {
+    fn rollout(&self, rollout: &Rollout) -> Result<(i64, bool), Error> {
         let variations = rollout
             .variations
             .as_ref()
             .filter(|v| !v.is_empty())
             .ok_or(Error::InvalidRollout)?;
+        let experiment = rollout.kind.as_deref() == Some("experiment");
 
         // compute user bucket (relative value: 0-1)
-        let bucket = self.bucket();
+        let bucket = self.bucket(rollout.seed);
 
         let mut sum = 0f64;
         for variation in variations {
@@ -239,7 +576,9 @@ impl<'a, 'u, S: Store> Evaluation<'a, 'u, S> {
 
             // user matches when passing bucket threshold
             if bucket < sum {
-                return variation.variation.ok_or(Error::InvalidRollout);
+                let index = variation.variation.ok_or(Error::InvalidRollout)?;
+                let in_experiment = experiment && !variation.untracked.unwrap_or(false);
+                return Ok((index, in_experiment));
             }
         }
 
@@ -250,18 +589,22 @@ impl<'a, 'u, S: Store> Evaluation<'a, 'u, S> {
 
     /// Determine the rollout bucket for the current user
     ///
+    /// A seed replaces flag key and salt in the hash input.
+    ///
     /// https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#rollouts
-    fn bucket(&self) -> f64 {
+    fn bucket(&self, seed: Option<i64>) -> f64 {
         // todo: support a custom user attribute
         // todo: support the secondary user identifier
 
         // compute SHA1 hash for user from flag, salt & user
+        let prefix = match seed {
+            Some(seed) => seed.to_string(),
+            None => format!("{}.{}", self.flag.key, self.flag.salt),
+        };
         let hash = &Sha1::new()
-            .chain(&self.flag.key)
+            .chain(prefix)
             .chain(".")
-            .chain(&self.flag.salt)
-            .chain(".")
-            .chain(self.user.key)
+            .chain(self.user.key())
             .finalize()[..];
         // hex string of the hash is cut to first 15 characters
         let mut hex: String = hash.encode_hex();
@@ -274,6 +617,62 @@ impl<'a, 'u, S: Store> Evaluation<'a, 'u, S> {
     }
 }
 
+/// Evaluates many flags for one user
+///
+/// All flags are read from one [snapshot](Store::snapshot) of the store
+/// and every flag is evaluated once, so prerequisites shared by several
+/// flags are not evaluated again.
+/// Meant to be short-lived, e.g. for the flags needed in a single request.
+pub struct Batch<'a, 'u> {
+    snapshot: StoreSnapshot,
+    user: &'a User<'u>,
+    variations: Variations,
+}
+
+impl<'a, 'u> Batch<'a, 'u> {
+    /// Create a batch reading flags from a snapshot of a [Store]
+    pub fn new<S: Store>(store: &S, user: &'a User<'u>) -> Self {
+        Self::with_snapshot(store.snapshot(), user)
+    }
+
+    /// Create a batch reading flags from a snapshot taken earlier
+    pub fn with_snapshot(snapshot: StoreSnapshot, user: &'a User<'u>) -> Self {
+        Self {
+            snapshot,
+            user,
+            variations: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The flags the batch reads from
+    pub fn snapshot(&self) -> &StoreSnapshot {
+        &self.snapshot
+    }
+
+    /// Read a flag from the snapshot
+    pub fn flag(&self, key: &str) -> Option<Arc<CompiledFlag>> {
+        self.snapshot.flag(key)
+    }
+
+    /// Evaluate a flag by key
+    pub fn evaluate(&self, key: &str) -> Result<Detail, Error> {
+        let flag = self.flag(key).ok_or(Error::FlagNotFound)?;
+        self.evaluate_flag(&flag)
+    }
+
+    /// Evaluate a flag read with [flag](Self::flag)
+    pub fn evaluate_flag(&self, flag: &CompiledFlag) -> Result<Detail, Error> {
+        Evaluation {
+            flag,
+            user: self.user,
+            store: &self.snapshot,
+            variations: Some(&self.variations),
+            parents: None,
+        }
+        .detail()
+    }
+}
+
 pub trait Evaluate {
     /// Determines the variation value for a flag
     ///
@@ -293,23 +692,207 @@ pub trait Evaluate {
 impl<S: Store> Evaluator<S> {
     /// Create an evaluator for a [Store]
     pub fn new(store: S) -> Self {
-        Self { store }
+        Self {
+            store,
+            hooks: Hooks::new(),
+        }
+    }
+
+    /// Run a [Hook] around every evaluation, after the hooks added before
+    pub fn with_hook<H: Hook + 'static>(mut self, hook: H) -> Self {
+        self.hooks.add(hook);
+        self
+    }
+
+    /// Evaluate a flag that was already retrieved from the [Store]
+    ///
+    /// Prerequisites are read from a [snapshot](Store::snapshot_for) of the store.
+    pub fn evaluate_flag(&self, flag: &CompiledFlag, user: &User) -> Result<Detail, Error> {
+        let context = EvaluationContext {
+            flag_key: &flag.key,
+            user,
+            default: None,
+        };
+        self.evaluate_with_hooks(
+            &context,
+            Some(flag),
+            |flag| self.detail(flag, user),
+            &|_, _| {},
+        )
+    }
+
+    /// Evaluate several flags for one user in a [Batch]
+    pub fn evaluate_many<'k, I>(
+        &self,
+        keys: I,
+        user: &User,
+    ) -> HashMap<String, Result<Detail, Error>>
+    where
+        I: IntoIterator<Item = &'k str>,
+    {
+        self.evaluate_many_observed(keys, user, |_, _| {})
+    }
+
+    /// Evaluate all flags for one user in a [Batch]
+    pub fn evaluate_all(&self, user: &User) -> HashMap<String, Result<Detail, Error>> {
+        self.evaluate_all_observed(user, |_, _| {})
+    }
+
+    /// Evaluate a flag by key, telling the hooks about the default value
+    ///
+    /// A value rejected by `check` fails the evaluation with [Error::InvalidVariationType].
+    /// `observe` is called with the flag and the result, unless the flag was not found.
+    pub(crate) fn evaluate_observed<C, F>(
+        &self,
+        key: &str,
+        user: &User,
+        default: Option<&serde_json::Value>,
+        check: C,
+        observe: F,
+    ) -> Result<Detail, Error>
+    where
+        C: Fn(&serde_json::Value) -> bool,
+        F: Fn(&CompiledFlag, &Result<Detail, Error>),
+    {
+        let context = EvaluationContext {
+            flag_key: key,
+            user,
+            default,
+        };
+        let checked = |result: Result<Detail, Error>| match result {
+            Ok(detail) if !check(&detail.value) => Err(Error::InvalidVariationType),
+            result => result,
+        };
+        match self.store.flag(key) {
+            // get flag and its prerequisites from the same version of the data
+            Some(flag) if !flag.prerequisites.is_empty() => {
+                let batch = Batch::with_snapshot(self.store.snapshot_for(key), user);
+                let flag = batch.flag(key);
+                let evaluate = |flag: &CompiledFlag| checked(batch.evaluate_flag(flag));
+                self.evaluate_with_hooks(&context, flag.as_deref(), evaluate, &observe)
+            }
+            flag => {
+                let evaluate = |flag: &CompiledFlag| {
+                    checked(Evaluation::new(&self.store, flag, user).detail())
+                };
+                self.evaluate_with_hooks(&context, flag.as_deref(), evaluate, &observe)
+            }
+        }
+    }
+
+    /// Same as [evaluate_many](Self::evaluate_many), calling `observe` like
+    /// [evaluate_observed](Self::evaluate_observed)
+    pub(crate) fn evaluate_many_observed<'k, I, F>(
+        &self,
+        keys: I,
+        user: &User,
+        observe: F,
+    ) -> HashMap<String, Result<Detail, Error>>
+    where
+        I: IntoIterator<Item = &'k str>,
+        F: Fn(&CompiledFlag, &Result<Detail, Error>),
+    {
+        let batch = Batch::new(&self.store, user);
+        keys.into_iter()
+            .map(|key| {
+                let context = EvaluationContext {
+                    flag_key: key,
+                    user,
+                    default: None,
+                };
+                let flag = batch.flag(key);
+                let evaluate = |flag: &CompiledFlag| batch.evaluate_flag(flag);
+                let result =
+                    self.evaluate_with_hooks(&context, flag.as_deref(), evaluate, &observe);
+                (key.to_string(), result)
+            })
+            .collect()
+    }
+
+    /// Same as [evaluate_all](Self::evaluate_all), calling `observe` like
+    /// [evaluate_observed](Self::evaluate_observed)
+    pub(crate) fn evaluate_all_observed<F>(
+        &self,
+        user: &User,
+        observe: F,
+    ) -> HashMap<String, Result<Detail, Error>>
+    where
+        F: Fn(&CompiledFlag, &Result<Detail, Error>),
+    {
+        let batch = Batch::new(&self.store, user);
+        batch
+            .snapshot()
+            .flags()
+            .iter()
+            .filter(|(_, flag)| !flag.deleted)
+            .map(|(key, flag)| {
+                let context = EvaluationContext {
+                    flag_key: key,
+                    user,
+                    default: None,
+                };
+                let evaluate = |flag: &CompiledFlag| batch.evaluate_flag(flag);
+                let result = self.evaluate_with_hooks(&context, Some(flag), evaluate, &observe);
+                (key.clone(), result)
+            })
+            .collect()
+    }
+
+    /// Evaluate a flag that was read from the store
+    ///
+    /// Only flags with prerequisites need a [snapshot](Store::snapshot_for),
+    /// to read the prerequisites from the same version of the data.
+    fn detail(&self, flag: &CompiledFlag, user: &User) -> Result<Detail, Error> {
+        if flag.prerequisites.is_empty() {
+            Evaluation::new(&self.store, flag, user).detail()
+        } else {
+            Batch::with_snapshot(self.store.snapshot_for(&flag.key), user).evaluate_flag(flag)
+        }
+    }
+
+    /// Run the hooks around the evaluation of a flag, `None` if it was not found
+    fn evaluate_with_hooks<E, F>(
+        &self,
+        context: &EvaluationContext,
+        flag: Option<&CompiledFlag>,
+        evaluate: E,
+        observe: &F,
+    ) -> Result<Detail, Error>
+    where
+        E: FnOnce(&CompiledFlag) -> Result<Detail, Error>,
+        F: Fn(&CompiledFlag, &Result<Detail, Error>),
+    {
+        self.hooks.run(context, || {
+            let flag = flag.ok_or(Error::FlagNotFound)?;
+            let result = evaluate(flag);
+            observe(flag, &result);
+            result
+        })
     }
 }
 
 impl<S: Store> Evaluate for Evaluator<S> {
     fn evaluate(&self, flag: &str, user: &User) -> Result<serde_json::Value, Error> {
-        // get flag from store
-        let flag = self.store.flag(flag).ok_or(Error::FlagNotFound)?;
-        // find variation based on rules
-        Evaluation::new(&self.store, &flag, user).run()
+        self.evaluate_observed(flag, user, None, |_| true, |_, _| {})
+            .map(|detail| detail.value)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Evaluation, User};
-    use crate::test_utils::{FlagBuilder, MockStore};
+    use super::{Error, ErrorKind, Evaluate, Evaluation, Evaluator, Reason, User};
+    use crate::{
+        compiled::CompiledFlag,
+        models::{
+            clause::{Clause, ClauseBuilder},
+            rule::Rule,
+            FeatureFlagState,
+        },
+        store::Store,
+        test_utils::{FlagBuilder, MockStore},
+    };
+    use serde_json::json;
+    use std::{cell::Cell, collections::HashMap, sync::Arc};
 
     fn setup() -> (User<'static>, MockStore) {
         let user = User::new("test-user");
@@ -317,6 +900,30 @@ mod tests {
         (user, store)
     }
 
+    #[test]
+    fn user_from_json() {
+        let user: User = serde_json::from_value(json!({
+            "key": "test-user",
+            "email": "test@example.com",
+            "name": null,
+            "custom": { "team": "platform" },
+            "privateAttributeNames": ["email"]
+        }))
+        .unwrap();
+        assert_eq!("test-user", user.key());
+        assert_eq!(
+            Some(json!("test@example.com")),
+            user.attribute("email").map(|v| v.into_owned())
+        );
+        assert_eq!(
+            Some(json!("platform")),
+            user.attribute("team").map(|v| v.into_owned())
+        );
+        assert_eq!(None, user.attribute("name"));
+        assert!(user.is_private("email"));
+        assert!(!user.is_private("team"));
+    }
+
     #[test]
     fn fallthrough() {
         let (user, mut store) = setup();
@@ -324,7 +931,7 @@ mod tests {
             .on()
             .with_key("eval_test")
             .with_fallthrough_variation(1)
-            .into_inner();
+            .compile();
         store.add(flag.clone());
         let eval = Evaluation::new(&store, &flag, &user);
         assert_eq!(1, eval.index().expect("failed to get variation index"));
@@ -338,7 +945,7 @@ mod tests {
             .with_key("eval_test")
             // 30/70 % split
             .with_fallthrough_rollout(vec![(0, 30000), (1, 70000)])
-            .into_inner();
+            .compile();
         store.add(flag.clone());
 
         let eval = Evaluation::new(&store, &flag, &user1);
@@ -356,10 +963,393 @@ mod tests {
             .on()
             .with_key("eval_test")
             .add_target(1, "test-user")
-            .into_inner();
+            .compile();
+        store.add(flag.clone());
+
+        let eval = Evaluation::new(&store, &flag, &user);
+        assert_eq!(1, eval.index().expect("failed to get variation index"));
+    }
+
+    #[test]
+    fn reasons() {
+        let (user, mut store) = setup();
+        let off = FlagBuilder::default().off().with_key("off_flag").compile();
+        store.add(off.clone());
+        let targeted = FlagBuilder::default()
+            .with_key("targeted_flag")
+            .add_target(1, "test-user")
+            .compile();
+        store.add(targeted.clone());
+        let dependent = FlagBuilder::default()
+            .with_key("dependent_flag")
+            .with_fallthrough_variation(1)
+            .add_prerequisite("targeted_flag", 1)
+            .add_prerequisite("off_flag", 1)
+            .compile();
+        store.add(dependent.clone());
+
+        let detail = Evaluation::new(&store, &off, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(Reason::Off, detail.reason);
+
+        let detail = Evaluation::new(&store, &targeted, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(Reason::TargetMatch, detail.reason);
+
+        let detail = Evaluation::new(&store, &dependent, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(0, detail.variation_index);
+        assert_eq!(
+            Reason::PrerequisiteFailed {
+                prerequisite_key: "off_flag".into()
+            },
+            detail.reason
+        );
+    }
+
+    #[test]
+    fn prerequisite_cycle() {
+        let (user, mut store) = setup();
+        for (key, prereq) in &[("a", "b"), ("b", "c"), ("c", "b")] {
+            let flag = FlagBuilder::default()
+                .with_key(*key)
+                .add_prerequisite(*prereq, 0)
+                .compile();
+            store.add(flag);
+        }
+        let dependent = FlagBuilder::default()
+            .with_key("dependent")
+            .add_prerequisite("a", 0)
+            .compile();
+        store.add(dependent.clone());
+
+        for key in &["a", "b", "c"] {
+            let flag = store.flag(key).unwrap();
+            assert_eq!(
+                Err(Error::InvalidPrerequisite),
+                Evaluation::new(&store, &flag, &user).detail()
+            );
+        }
+        // flags depending on a cycle are malformed as well
+        assert_eq!(
+            Err(Error::InvalidPrerequisite),
+            Evaluation::new(&store, &dependent, &user).detail()
+        );
+        let all = Evaluator::new(store).evaluate_all(&user);
+        assert_eq!(4, all.len());
+        assert!(all.values().all(|r| r == &Err(Error::InvalidPrerequisite)));
+    }
+
+    #[test]
+    fn malformed() {
+        let (user, mut store) = setup();
+        let flag = FlagBuilder::default()
+            .with_key("malformed_flag")
+            .add_target(3, "someone-else")
+            .add_rule(
+                Rule::builder()
+                    .variation(7)
+                    .clauses(vec![clause("country", "in", &["de"])].into_iter())
+                    .into(),
+            )
+            .compile();
+        store.add(flag.clone());
+        // broken target and rule don't apply to the user
+        let detail = Evaluation::new(&store, &flag, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(
+            Reason::Fallthrough {
+                in_experiment: false
+            },
+            detail.reason
+        );
+
+        // only users reaching the broken parts fail
+        let targeted = User::new("someone-else");
+        let result = Evaluation::new(&store, &flag, &targeted).detail();
+        assert_eq!(Some(Error::IndexOutOfRange), result.err());
+        let matched = User::new("test-user").with_attribute("country", "de");
+        let result = Evaluation::new(&store, &flag, &matched).detail();
+        assert_eq!(Some(Error::IndexOutOfRange), result.err());
+
+        // off flags don't need valid targeting
+        let flag = FlagBuilder::default()
+            .off()
+            .add_target(3, "someone-else")
+            .compile();
+        let detail = Evaluation::new(&store, &flag, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(Reason::Off, detail.reason);
+    }
+
+    #[test]
+    fn unsupported_operator() {
+        let (user, store) = setup();
+        let flag = FlagBuilder::default()
+            .add_target(1, "targeted")
+            .add_rule(
+                Rule::builder()
+                    .variation(1)
+                    .clauses(vec![clause("key", "segmentMatch", &["beta-users"])].into_iter())
+                    .into(),
+            )
+            .compile();
+        // the fallthrough is not served in place of the rule
+        let result = Evaluation::new(&store, &flag, &user).detail();
+        assert_eq!(Some(Error::UnsupportedOperator), result.err());
+        assert_eq!(ErrorKind::Exception, Error::UnsupportedOperator.kind());
+
+        // targets before the rule still apply
+        let targeted = User::new("targeted");
+        let detail = Evaluation::new(&store, &flag, &targeted)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(Reason::TargetMatch, detail.reason);
+    }
+
+    fn clause(attribute: &str, op: &str, values: &[&str]) -> ClauseBuilder {
+        Clause::builder()
+            .attribute(attribute)
+            .op(op)
+            .values(values.iter().map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn rules() {
+        let (_, mut store) = setup();
+        let flag = FlagBuilder::default()
+            .with_key("rules_flag")
+            .with_variations(vec!["a", "b", "c"])
+            .add_rule(
+                Rule::builder()
+                    .id("internal")
+                    .variation(1)
+                    .clauses(
+                        vec![
+                            clause("email", "endsWith", &["@example.com"]),
+                            clause("country", "in", &["de", "fr"]),
+                        ]
+                        .into_iter(),
+                    )
+                    .into(),
+            )
+            .add_rule(
+                Rule::builder()
+                    .variation(2)
+                    .clauses(vec![clause("groups", "in", &["beta"])].into_iter())
+                    .into(),
+            )
+            .compile();
         store.add(flag.clone());
 
+        let user = User::new("test-user")
+            .with_attribute("email", "test@example.com")
+            .with_attribute("country", "de");
+        let detail = Evaluation::new(&store, &flag, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!("b", detail.value);
+        assert_eq!(
+            Reason::RuleMatch {
+                rule_index: 0,
+                rule_id: Some("internal".into()),
+                in_experiment: false,
+            },
+            detail.reason
+        );
+
+        // any element of an array attribute can match
+        let user = User::new("test-user")
+            .with_attribute("email", "test@example.com")
+            .with_attribute("groups", vec!["alpha", "beta"]);
+        let detail = Evaluation::new(&store, &flag, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!("c", detail.value);
+
+        let user = User::new("test-user");
+        let detail = Evaluation::new(&store, &flag, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(
+            Reason::Fallthrough {
+                in_experiment: false
+            },
+            detail.reason
+        );
+    }
+
+    #[test]
+    fn typed_clause_values() {
+        // clause values are strings, numbers or booleans in LaunchDarkly payloads
+        let mut data = serde_json::to_value(FlagBuilder::default().into_inner()).unwrap();
+        data["rules"] = json!([{
+            "id": "adults",
+            "variation": 1,
+            "clauses": [
+                { "attribute": "age", "op": "greaterThan", "values": [25] },
+                { "attribute": "beta", "op": "in", "values": [true] }
+            ]
+        }]);
+        let flag: FeatureFlagState = serde_json::from_value(data).expect("invalid flag");
+        let flag = CompiledFlag::from(flag);
+        let (_, store) = setup();
+
+        let user = User::new("test-user")
+            .with_attribute("age", 30)
+            .with_attribute("beta", true);
         let eval = Evaluation::new(&store, &flag, &user);
         assert_eq!(1, eval.index().expect("failed to get variation index"));
+
+        let user = User::new("test-user")
+            .with_attribute("age", 20)
+            .with_attribute("beta", true);
+        let eval = Evaluation::new(&store, &flag, &user);
+        assert_eq!(0, eval.index().expect("failed to get variation index"));
+    }
+
+    #[test]
+    fn negated_clause() {
+        let (user, mut store) = setup();
+        let negated = clause("country", "in", &["de"]).negate(true);
+        let flag = FlagBuilder::default()
+            .add_rule(
+                Rule::builder()
+                    .variation(1)
+                    .clauses(vec![negated].into_iter())
+                    .into(),
+            )
+            .compile();
+        store.add(flag.clone());
+
+        // missing attributes never match, even when negated
+        let eval = Evaluation::new(&store, &flag, &user);
+        assert_eq!(0, eval.index().expect("failed to get variation index"));
+
+        let user = User::new("test-user").with_attribute("country", "fr");
+        let eval = Evaluation::new(&store, &flag, &user);
+        assert_eq!(1, eval.index().expect("failed to get variation index"));
+    }
+
+    #[test]
+    fn experiment() {
+        let (user, mut store) = setup();
+        // same buckets as the 30/70 split in `fallthrough_rollout`
+        let flag = FlagBuilder::default()
+            .with_key("eval_test")
+            .with_fallthrough_experiment(vec![(0, 30000, false), (1, 70000, true)])
+            .compile();
+        store.add(flag.clone());
+
+        let detail = Evaluation::new(&store, &flag, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(1, detail.variation_index);
+        assert_eq!(
+            Reason::Fallthrough {
+                in_experiment: false
+            },
+            detail.reason
+        );
+
+        let user = User::new("my-other-user");
+        let detail = Evaluation::new(&store, &flag, &user)
+            .detail()
+            .expect("evaluation failed");
+        assert_eq!(0, detail.variation_index);
+        assert_eq!(
+            Reason::Fallthrough {
+                in_experiment: true
+            },
+            detail.reason
+        );
+        assert!(detail.reason.is_experiment(&flag));
+        assert_eq!(
+            serde_json::json!({ "kind": "FALLTHROUGH", "inExperiment": true }),
+            serde_json::to_value(&detail.reason).unwrap()
+        );
+    }
+
+    /// Counts the reads of every flag
+    struct CountingStore {
+        store: MockStore,
+        reads: Cell<usize>,
+    }
+
+    impl Store for CountingStore {
+        fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>> {
+            self.reads.set(self.reads.get() + 1);
+            self.store.flag(name)
+        }
+
+        fn export_all(&self) -> HashMap<String, Arc<CompiledFlag>> {
+            self.reads.set(self.reads.get() + 1);
+            self.store.export_all()
+        }
+
+        fn initialized(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn batch() {
+        let (user, mut store) = setup();
+        store.add(FlagBuilder::default().with_key("prereq").into_inner());
+        for key in &["a", "b"] {
+            let flag = FlagBuilder::default()
+                .with_key(*key)
+                .add_prerequisite("prereq", 0)
+                .with_fallthrough_variation(1);
+            store.add(flag.into_inner());
+        }
+        let evaluator = Evaluator::new(CountingStore {
+            store,
+            reads: Cell::default(),
+        });
+
+        let results = evaluator.evaluate_many(vec!["a", "b", "prereq", "missing"], &user);
+        assert_eq!(4, results.len());
+        assert_eq!(Ok(true.into()), results["a"].clone().map(|d| d.value));
+        assert_eq!(Ok(true.into()), results["b"].clone().map(|d| d.value));
+        assert_eq!(Ok(false.into()), results["prereq"].clone().map(|d| d.value));
+        assert_eq!(Err(Error::FlagNotFound), results["missing"]);
+        // all flags are read from the store at once
+        assert_eq!(1, evaluator.store.reads.get());
+
+        // same results as single evaluations
+        let all = evaluator.evaluate_all(&user);
+        assert_eq!(3, all.len());
+        for key in &["a", "b", "prereq"] {
+            let flag = evaluator.store.flag(key).unwrap();
+            assert_eq!(evaluator.evaluate_flag(&flag, &user), all[*key]);
+        }
+    }
+
+    #[test]
+    fn single_reads() {
+        let (user, mut store) = setup();
+        store.add(FlagBuilder::default().with_key("prereq").into_inner());
+        let flag = FlagBuilder::default()
+            .with_key("dependent")
+            .add_prerequisite("prereq", 0)
+            .with_fallthrough_variation(1);
+        store.add(flag.into_inner());
+        let evaluator = Evaluator::new(CountingStore {
+            store,
+            reads: Cell::default(),
+        });
+
+        // without prerequisites only the flag is read
+        assert_eq!(Ok(false.into()), evaluator.evaluate("prereq", &user));
+        assert_eq!(1, evaluator.store.reads.get());
+        // prerequisites are read from a snapshot
+        assert_eq!(Ok(true.into()), evaluator.evaluate("dependent", &user));
+        assert_eq!(3, evaluator.store.reads.get());
     }
 }
@@ -0,0 +1,1257 @@
+use crate::{
+    diagnostics::{DiagnosticEvent, Diagnostics},
+    evaluator::{Detail, Error, Reason, User, BUILT_IN_ATTRIBUTES},
+    models::FeatureFlagState,
+};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    Future,
+};
+use http::{
+    header::{InvalidHeaderValue, AUTHORIZATION, CONTENT_TYPE, DATE},
+    HeaderMap, HeaderValue, Request, StatusCode, Uri,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::HttpsConnector;
+use lru::LruCache;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, mem,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task::{self, JoinHandle},
+    time,
+};
+use tracing::{debug, warn};
+
+/// default URL for posting analytics events
+const DEFAULT_EVENTS_URL: &str = "https://events.launchdarkly.com/bulk";
+
+/// default URL for posting diagnostic events
+const DEFAULT_DIAGNOSTIC_URL: &str = "https://events.launchdarkly.com/diagnostic";
+
+/// Version of the event payload format
+const EVENT_SCHEMA: &str = "3";
+
+/// An analytics event sent to LaunchDarkly
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Event {
+    Feature(FeatureEvent),
+    /// Evaluation recorded while debugging is enabled for a flag
+    ///
+    /// Always contains the full user.
+    Debug(FeatureEvent),
+    Identify(IdentifyEvent),
+    Custom(CustomEvent),
+    Index(IndexEvent),
+    Summary(SummaryEvent),
+}
+
+/// Full-fidelity record of a single evaluation
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeatureEvent {
+    pub creation_date: u64,
+    pub key: String,
+    #[serde(flatten)]
+    pub user: UserRef,
+    pub value: serde_json::Value,
+    /// Missing for failed evaluations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variation: Option<usize>,
+    pub version: u64,
+    /// Only included for evaluations that are part of an experiment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
+    /// Value the application falls back to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+}
+
+impl FeatureEvent {
+    /// Create an event from the result of an evaluation
+    pub fn new(flag: &FeatureFlagState, user: EventUser, detail: &Detail) -> Self {
+        Self {
+            creation_date: now_millis(),
+            key: flag.key.clone(),
+            user: UserRef::Inline(user),
+            value: detail.value.clone(),
+            variation: Some(detail.variation_index),
+            version: flag.version,
+            reason: None,
+            default: None,
+        }
+    }
+
+    /// Create an event for an evaluation that failed, resulting in `default`
+    pub fn failed(
+        flag: &FeatureFlagState,
+        user: EventUser,
+        default: Option<&serde_json::Value>,
+    ) -> Self {
+        Self {
+            creation_date: now_millis(),
+            key: flag.key.clone(),
+            user: UserRef::Inline(user),
+            value: default.cloned().unwrap_or_default(),
+            variation: None,
+            version: flag.version,
+            reason: None,
+            default: default.cloned(),
+        }
+    }
+}
+
+/// Reports a user explicitly, e.g. after logging in
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentifyEvent {
+    pub creation_date: u64,
+    pub key: String,
+    pub user: EventUser,
+}
+
+/// Event tracked by the application, e.g. a conversion for an experiment
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomEvent {
+    pub creation_date: u64,
+    pub key: String,
+    #[serde(flatten)]
+    pub user: UserRef,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metric_value: Option<f64>,
+}
+
+/// Announces a user, so other events only need to reference it by key
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexEvent {
+    pub creation_date: u64,
+    pub user: EventUser,
+}
+
+/// Counts of all evaluations since the last flush
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SummaryEvent {
+    pub start_date: u64,
+    pub end_date: u64,
+    pub features: HashMap<String, FlagSummary>,
+}
+
+impl SummaryEvent {
+    /// Count an evaluation
+    fn add(&mut self, event: &FeatureEvent) {
+        if self.features.is_empty() {
+            self.start_date = event.creation_date;
+        }
+        self.end_date = self.end_date.max(event.creation_date);
+        let summary = self.features.entry(event.key.clone()).or_default();
+        if event.default.is_some() {
+            summary.default = event.default.clone();
+        }
+        let counters = &mut summary.counters;
+        match counters
+            .iter_mut()
+            .find(|c| c.variation == event.variation && c.version == event.version)
+        {
+            Some(counter) => counter.count += 1,
+            None => counters.push(FlagCounter {
+                value: event.value.clone(),
+                variation: event.variation,
+                version: event.version,
+                count: 1,
+            }),
+        }
+    }
+}
+
+/// Evaluation counts for a single flag
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FlagSummary {
+    /// Last default value passed by the application
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+    pub counters: Vec<FlagCounter>,
+}
+
+/// Number of evaluations resulting in the same variation
+#[derive(Debug, Clone, Serialize)]
+pub struct FlagCounter {
+    pub value: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variation: Option<usize>,
+    pub version: u64,
+    pub count: u64,
+}
+
+/// Reference to the user of an event
+#[derive(Debug, Clone, Serialize)]
+pub enum UserRef {
+    /// Only the key, the user is announced by an [IndexEvent]
+    #[serde(rename = "userKey")]
+    Key(String),
+    /// Full user as part of the event
+    #[serde(rename = "user")]
+    Inline(EventUser),
+}
+
+/// User as represented in events
+///
+/// Private attributes are removed and only listed by name.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventUser {
+    pub(crate) key: String,
+    #[serde(flatten)]
+    attributes: BTreeMap<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    custom: BTreeMap<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    private_attrs: Vec<String>,
+}
+
+impl EventUser {
+    /// Convert a user, hiding private attributes according to the config
+    pub fn new(user: &User, config: &Config) -> Self {
+        let mut event_user = Self {
+            key: user.key().into(),
+            attributes: BTreeMap::new(),
+            custom: BTreeMap::new(),
+            private_attrs: Vec::new(),
+        };
+        for (name, value) in user.attributes() {
+            let private = config.all_attributes_private
+                || config.private_attribute_names.contains(name)
+                || user.is_private(name);
+            // `anonymous` is required to process the event
+            if private && name != "anonymous" {
+                event_user.private_attrs.push(name.into());
+            } else if BUILT_IN_ATTRIBUTES.contains(&name) {
+                event_user.attributes.insert(name.into(), value.clone());
+            } else {
+                event_user.custom.insert(name.into(), value.clone());
+            }
+        }
+        event_user.private_attrs.sort();
+        event_user
+    }
+}
+
+/// Milliseconds since the unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Response of a successful [EventSender::send]
+#[derive(Debug, Clone, Default)]
+pub struct SendResult {
+    /// Time reported by the server, in milliseconds since the unix epoch
+    pub server_time: Option<u64>,
+}
+
+/// Delivers a batch of events to LaunchDarkly
+pub trait EventSender {
+    type Error: fmt::Display;
+    type Future: Future<Output = Result<SendResult, Self::Error>> + Send;
+
+    /// Send a batch of events
+    fn send(&self, events: Vec<Event>) -> Self::Future;
+
+    /// Send a single diagnostic event
+    fn send_diagnostic(&self, event: DiagnosticEvent) -> Self::Future;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error("Failed to serialize events: {0}")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("Failed to build request: {0}")]
+    Request(#[from] http::Error),
+
+    #[error("Failed to send events: {0}")]
+    Http(#[from] hyper::Error),
+
+    #[error("Unexpected response status: {0}")]
+    Status(StatusCode),
+}
+
+/// [EventSender] posting events to the LaunchDarkly events API
+pub struct HttpEventSender {
+    client: Client<HttpsConnector<HttpConnector>>,
+    token: HeaderValue,
+    url: Uri,
+    diagnostic_url: Uri,
+}
+
+impl HttpEventSender {
+    /// Create an [EventSender] using an SDK token
+    pub fn new<T: AsRef<str>>(token: T) -> Result<Self, InvalidHeaderValue> {
+        Self::with_urls(
+            token,
+            Uri::from_static(DEFAULT_EVENTS_URL),
+            Uri::from_static(DEFAULT_DIAGNOSTIC_URL),
+        )
+    }
+
+    /// Create an [EventSender] posting to other URLs,
+    /// like a relay proxy or a test harness
+    pub fn with_urls<T: AsRef<str>>(
+        token: T,
+        url: Uri,
+        diagnostic_url: Uri,
+    ) -> Result<Self, InvalidHeaderValue> {
+        let token = HeaderValue::from_str(token.as_ref())?;
+        let client = Client::builder().build(HttpsConnector::with_native_roots());
+        Ok(Self {
+            client,
+            token,
+            url,
+            diagnostic_url,
+        })
+    }
+
+    /// Post a json payload
+    fn post<T: Serialize>(
+        &self,
+        url: &Uri,
+        payload: &T,
+    ) -> BoxFuture<'static, Result<SendResult, SendError>> {
+        let client = self.client.clone();
+        let request = serde_json::to_vec(payload)
+            .map_err(SendError::from)
+            .and_then(|body| {
+                Request::post(url.clone())
+                    .header(AUTHORIZATION, self.token.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .header("X-LaunchDarkly-Event-Schema", EVENT_SCHEMA)
+                    .body(Body::from(body))
+                    .map_err(Into::into)
+            });
+        async move {
+            let res = client.request(request?).await?;
+            if !res.status().is_success() {
+                return Err(SendError::Status(res.status()));
+            }
+            Ok(SendResult {
+                server_time: server_time(res.headers()),
+            })
+        }
+        .boxed()
+    }
+}
+
+impl EventSender for HttpEventSender {
+    type Error = SendError;
+    type Future = BoxFuture<'static, Result<SendResult, Self::Error>>;
+
+    fn send(&self, events: Vec<Event>) -> Self::Future {
+        self.post(&self.url, &events)
+    }
+
+    fn send_diagnostic(&self, event: DiagnosticEvent) -> Self::Future {
+        self.post(&self.diagnostic_url, &event)
+    }
+}
+
+/// Read the time from the `Date` header of a response
+fn server_time(headers: &HeaderMap) -> Option<u64> {
+    let date = headers.get(DATE)?.to_str().ok()?;
+    let time = httpdate::parse_http_date(date).ok()?;
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .ok()
+}
+
+/// Configuration for an [EventProcessor]
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Max number of events held in memory between flushes
+    pub capacity: usize,
+    /// Interval for sending events in the background
+    pub flush_interval: Duration,
+    /// Hide all user attributes except the key from events
+    pub all_attributes_private: bool,
+    /// User attributes hidden from events for all users
+    pub private_attribute_names: HashSet<String>,
+    /// Number of user keys remembered for deduplicating users
+    pub user_keys_capacity: usize,
+    /// Interval for forgetting all remembered user keys
+    ///
+    /// Users are announced again after this.
+    pub user_keys_flush_interval: Duration,
+    /// Include the full user in every feature event
+    /// instead of announcing it once
+    pub inline_users_in_events: bool,
+    /// Do not send diagnostic events
+    pub diagnostic_opt_out: bool,
+    /// Interval for sending diagnostic statistics
+    pub diagnostic_recording_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            flush_interval: Duration::from_secs(5),
+            all_attributes_private: false,
+            private_attribute_names: HashSet::new(),
+            user_keys_capacity: 1000,
+            user_keys_flush_interval: Duration::from_secs(300),
+            inline_users_in_events: false,
+            diagnostic_opt_out: false,
+            diagnostic_recording_interval: Duration::from_secs(900),
+        }
+    }
+}
+
+/// Instructions for the background task
+enum Command {
+    Evaluation {
+        event: FeatureEvent,
+        track_events: bool,
+        debug_events_until_date: Option<u64>,
+    },
+    Event(Event),
+    Flush(oneshot::Sender<()>),
+    Close(oneshot::Sender<()>),
+}
+
+/// Collects analytics events and sends them in batches
+///
+/// Sending happens in a background task which is spawned by [start](Self::start).
+/// Events recorded before that are buffered.
+///
+/// Dropping the processor aborts the task without sending pending events,
+/// use [close](Self::close) to deliver them.
+pub struct EventProcessor {
+    config: Config,
+    diagnostics: Option<Arc<Diagnostics>>,
+    tx: mpsc::Sender<Command>,
+    /// Task waiting to be spawned, behind a mutex to keep the processor `Sync`
+    dispatch: Mutex<Option<BoxFuture<'static, ()>>>,
+    /// Running task, taken by [close](Self::close)
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl EventProcessor {
+    /// Create a processor delivering events through a [EventSender]
+    pub fn new<S>(sender: S, config: Config) -> Self
+    where
+        S: EventSender + Send + Sync + 'static,
+    {
+        Self::build(sender, config, None)
+    }
+
+    /// Create a processor which also sends diagnostic events
+    ///
+    /// Diagnostics are not sent if [Config::diagnostic_opt_out] is set.
+    pub fn with_diagnostics<S>(sender: S, config: Config, diagnostics: Arc<Diagnostics>) -> Self
+    where
+        S: EventSender + Send + Sync + 'static,
+    {
+        Self::build(sender, config, Some(diagnostics))
+    }
+
+    fn build<S>(sender: S, config: Config, diagnostics: Option<Arc<Diagnostics>>) -> Self
+    where
+        S: EventSender + Send + Sync + 'static,
+    {
+        let diagnostics = diagnostics.filter(|_| !config.diagnostic_opt_out);
+        let (tx, rx) = mpsc::channel(config.capacity.max(1));
+        let outbox = Outbox::new(config.clone(), diagnostics.clone());
+        Self {
+            config,
+            diagnostics,
+            tx,
+            dispatch: Mutex::new(Some(dispatch(sender, outbox, rx).boxed())),
+            task: Mutex::new(None),
+        }
+    }
+
+    /// Recorder for diagnostic data, unless diagnostics are disabled
+    pub fn diagnostics(&self) -> Option<&Arc<Diagnostics>> {
+        self.diagnostics.as_ref()
+    }
+
+    /// Spawn the background task
+    ///
+    /// Does nothing when already started.
+    pub fn start(&mut self) {
+        let dispatch = self.dispatch.get_mut().unwrap().take();
+        if let Some(dispatch) = dispatch {
+            *self.task.get_mut().unwrap() = Some(task::spawn(dispatch));
+        }
+    }
+
+    /// Record the result of an evaluation
+    ///
+    /// Every evaluation is counted in the summary, but only
+    /// flags with `trackEvents` produce a feature event.
+    /// Experiments always produce a feature event, including the reason.
+    /// Debug events are sent while `debugEventsUntilDate` is in the future.
+    pub fn record_evaluation(&self, flag: &FeatureFlagState, user: &User, detail: &Detail) {
+        self.record_evaluation_with_default(flag, user, Ok(detail), None);
+    }
+
+    /// Record the result of an evaluation the application passed a `default` to
+    ///
+    /// Failed evaluations are recorded with the default value and without
+    /// a variation, like the application sees them.
+    pub fn record_evaluation_with_default(
+        &self,
+        flag: &FeatureFlagState,
+        user: &User,
+        result: Result<&Detail, &Error>,
+        default: Option<&serde_json::Value>,
+    ) {
+        let user = EventUser::new(user, &self.config);
+        let (mut event, experiment) = match result {
+            Ok(detail) => {
+                let mut event = FeatureEvent::new(flag, user, detail);
+                let experiment = detail.reason.is_experiment(flag);
+                if experiment {
+                    event.reason = Some(detail.reason.clone());
+                }
+                (event, experiment)
+            }
+            Err(_) => (FeatureEvent::failed(flag, user, default), false),
+        };
+        event.default = default.cloned();
+        self.enqueue(Command::Evaluation {
+            event,
+            track_events: flag.track_events || experiment,
+            debug_events_until_date: flag.debug_events_until_date,
+        });
+    }
+
+    /// Record that a user was identified
+    pub fn identify(&self, user: &User) {
+        let user = EventUser::new(user, &self.config);
+        self.send(Event::Identify(IdentifyEvent {
+            creation_date: now_millis(),
+            key: user.key.clone(),
+            user,
+        }));
+    }
+
+    /// Record a custom event
+    ///
+    /// The metric value is used by numeric experiment metrics.
+    pub fn track(
+        &self,
+        key: &str,
+        user: &User,
+        data: Option<serde_json::Value>,
+        metric_value: Option<f64>,
+    ) {
+        let user = EventUser::new(user, &self.config);
+        self.send(Event::Custom(CustomEvent {
+            creation_date: now_millis(),
+            key: key.into(),
+            user: UserRef::Inline(user),
+            data,
+            metric_value,
+        }));
+    }
+
+    /// Queue an event for sending
+    ///
+    /// The event is dropped if the queue is full.
+    pub fn send(&self, event: Event) {
+        self.enqueue(Command::Event(event));
+    }
+
+    fn enqueue(&self, cmd: Command) {
+        match self.tx.try_send(cmd) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("event queue is full, dropping event");
+                if let Some(diagnostics) = &self.diagnostics {
+                    diagnostics.record_dropped_event();
+                }
+            }
+            Err(TrySendError::Closed(_)) => debug!("event processor is closed, dropping event"),
+        }
+    }
+
+    /// Send all pending events
+    ///
+    /// Resolves once they were delivered, or immediately
+    /// if the processor is not running.
+    pub async fn flush(&self) {
+        if self.task.lock().unwrap().is_none() {
+            return;
+        }
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(Command::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// Send all pending events and stop the background task
+    ///
+    /// Events recorded afterwards are dropped.
+    pub async fn close(&self) {
+        let task = self.task.lock().unwrap().take();
+        let task = match task {
+            Some(task) => task,
+            None => return,
+        };
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(Command::Close(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+        let _ = task.await;
+    }
+}
+
+impl Drop for EventProcessor {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().unwrap() {
+            task.abort();
+        }
+    }
+}
+
+/// Events waiting to be sent
+///
+/// Owned by the background task.
+struct Outbox {
+    config: Config,
+    diagnostics: Option<Arc<Diagnostics>>,
+    events: Vec<Event>,
+    summary: SummaryEvent,
+    /// users announced since the last flush of the user keys
+    users: LruCache<String, ()>,
+    /// time reported by the server on the last successful send
+    last_server_time: Option<u64>,
+}
+
+impl Outbox {
+    fn new(config: Config, diagnostics: Option<Arc<Diagnostics>>) -> Self {
+        Self {
+            diagnostics,
+            events: Vec::new(),
+            summary: SummaryEvent::default(),
+            users: LruCache::new(config.user_keys_capacity.max(1)),
+            last_server_time: None,
+            config,
+        }
+    }
+
+    fn add_evaluation(
+        &mut self,
+        mut event: FeatureEvent,
+        track_events: bool,
+        debug_events_until_date: Option<u64>,
+    ) {
+        self.summary.add(&event);
+        if self.debugging(debug_events_until_date) {
+            self.push(Event::Debug(event.clone()));
+        }
+        let inline = track_events && self.config.inline_users_in_events;
+        if let UserRef::Inline(user) = &event.user {
+            // no need to announce a user which is part of the event
+            if !self.notice_user(user) {
+                if let Some(diagnostics) = &self.diagnostics {
+                    diagnostics.record_deduplicated_user();
+                }
+            } else if !inline {
+                let index = IndexEvent {
+                    creation_date: event.creation_date,
+                    user: user.clone(),
+                };
+                self.push(Event::Index(index));
+            }
+        }
+        if !track_events {
+            return;
+        }
+        if !inline {
+            if let UserRef::Inline(user) = event.user {
+                event.user = UserRef::Key(user.key);
+            }
+        }
+        self.push(Event::Feature(event));
+    }
+
+    /// Add an event recorded by the application
+    ///
+    /// Users of custom events are announced like those of feature events.
+    fn add_event(&mut self, mut event: Event) {
+        match &mut event {
+            Event::Identify(identify) => {
+                // the identify event announces the user itself
+                self.notice_user(&identify.user);
+            }
+            Event::Custom(custom) => {
+                let user = match &custom.user {
+                    UserRef::Inline(user) => user.clone(),
+                    UserRef::Key(_) => return self.push(event),
+                };
+                let inline = self.config.inline_users_in_events;
+                if !self.notice_user(&user) {
+                    if let Some(diagnostics) = &self.diagnostics {
+                        diagnostics.record_deduplicated_user();
+                    }
+                } else if !inline {
+                    let index = IndexEvent {
+                        creation_date: custom.creation_date,
+                        user: user.clone(),
+                    };
+                    self.push(Event::Index(index));
+                }
+                if !inline {
+                    custom.user = UserRef::Key(user.key);
+                }
+            }
+            _ => {}
+        }
+        self.push(event);
+    }
+
+    /// Whether debugging is enabled until a point in time
+    ///
+    /// Compares against the server time as well, in case the local clock is behind.
+    fn debugging(&self, until: Option<u64>) -> bool {
+        let until = match until {
+            Some(until) => until,
+            None => return false,
+        };
+        until > now_millis() && until > self.last_server_time.unwrap_or_default()
+    }
+
+    /// Remember a user, returns true if it was not seen before
+    fn notice_user(&mut self, user: &EventUser) -> bool {
+        self.users.put(user.key.clone(), ()).is_none()
+    }
+
+    fn push(&mut self, event: Event) {
+        if self.events.len() >= self.config.capacity {
+            warn!("event buffer is full, dropping event");
+            if let Some(diagnostics) = &self.diagnostics {
+                diagnostics.record_dropped_event();
+            }
+            return;
+        }
+        self.events.push(event);
+    }
+
+    /// Take all events including the summary
+    fn take(&mut self) -> Vec<Event> {
+        let mut events = mem::take(&mut self.events);
+        if !self.summary.features.is_empty() {
+            events.push(Event::Summary(mem::take(&mut self.summary)));
+        }
+        events
+    }
+}
+
+/// Background task buffering events and sending them periodically
+async fn dispatch<S>(sender: S, mut outbox: Outbox, mut rx: mpsc::Receiver<Command>)
+where
+    S: EventSender,
+{
+    let config = outbox.config.clone();
+    let mut flush_interval = time::interval(config.flush_interval);
+    let mut user_keys_interval = time::interval(config.user_keys_flush_interval);
+    // statistics are sent after the first period
+    let mut diagnostic_interval = time::interval_at(
+        time::Instant::now() + config.diagnostic_recording_interval,
+        config.diagnostic_recording_interval,
+    );
+    let diagnostics = outbox.diagnostics.clone();
+    if let Some(diagnostics) = &diagnostics {
+        send_diagnostic(&sender, diagnostics.init_event(&config)).await;
+    }
+    loop {
+        tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(Command::Evaluation { event, track_events, debug_events_until_date }) => {
+                    outbox.add_evaluation(event, track_events, debug_events_until_date);
+                }
+                Some(Command::Event(event)) => outbox.add_event(event),
+                Some(Command::Flush(done)) => {
+                    flush(&sender, &mut outbox).await;
+                    let _ = done.send(());
+                }
+                Some(Command::Close(done)) => {
+                    flush(&sender, &mut outbox).await;
+                    let _ = done.send(());
+                    return;
+                }
+                None => {
+                    flush(&sender, &mut outbox).await;
+                    return;
+                }
+            },
+            _ = flush_interval.tick() => flush(&sender, &mut outbox).await,
+            _ = user_keys_interval.tick() => outbox.users.clear(),
+            _ = diagnostic_interval.tick(), if diagnostics.is_some() => {
+                if let Some(diagnostics) = &diagnostics {
+                    send_diagnostic(&sender, diagnostics.stats_event(outbox.events.len())).await;
+                }
+            }
+        }
+    }
+}
+
+async fn send_diagnostic<S: EventSender>(sender: &S, event: DiagnosticEvent) {
+    if let Err(error) = sender.send_diagnostic(event).await {
+        warn!(%error, "failed sending diagnostic event");
+    }
+}
+
+/// Send out the buffered events
+async fn flush<S: EventSender>(sender: &S, outbox: &mut Outbox) {
+    let events = outbox.take();
+    if events.is_empty() {
+        return;
+    }
+    debug!(num_events = events.len(), "sending events");
+    if let Some(diagnostics) = &outbox.diagnostics {
+        diagnostics.record_batch(events.len());
+    }
+    match sender.send(events).await {
+        Ok(SendResult { server_time }) => {
+            if server_time.is_some() {
+                outbox.last_server_time = server_time;
+            }
+        }
+        Err(error) => warn!(%error, "failed sending events"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        now_millis, server_time, Config, Event, EventProcessor, EventUser, FeatureEvent, Outbox,
+    };
+    use crate::{
+        evaluator::{Detail, Reason, User},
+        test_utils::{FlagBuilder, MockEventSender},
+    };
+    use http::{header::DATE, HeaderMap};
+    use serde_json::json;
+
+    fn detail() -> Detail {
+        Detail {
+            value: true.into(),
+            variation_index: 1,
+            reason: Reason::Fallthrough {
+                in_experiment: false,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn only_tracked_flags() {
+        let sender = MockEventSender::new();
+        let mut processor = EventProcessor::new(sender.clone(), Config::default());
+        processor.start();
+
+        let user = User::new("test-user");
+        let untracked = FlagBuilder::default().with_key("untracked").into_inner();
+        processor.record_evaluation(&untracked, &user, &detail());
+        let tracked = FlagBuilder::default()
+            .with_key("tracked")
+            .track_events()
+            .into_inner();
+        processor.record_evaluation(&tracked, &user, &detail());
+        processor.flush().await;
+
+        let events = sender.events_of_kind("feature");
+        assert_eq!(1, events.len());
+        assert_eq!("tracked", events[0]["key"]);
+        assert_eq!("test-user", events[0]["userKey"]);
+        assert_eq!(None, events[0].get("user"));
+        assert_eq!(1, events[0]["variation"]);
+        assert_eq!(true, events[0]["value"]);
+
+        // both evaluations are counted
+        let summary = sender.events_of_kind("summary");
+        assert_eq!(1, summary.len());
+        assert_eq!(
+            json!([{ "value": true, "variation": 1, "version": 0, "count": 1 }]),
+            summary[0]["features"]["untracked"]["counters"]
+        );
+        assert_eq!(
+            json!([{ "value": true, "variation": 1, "version": 0, "count": 1 }]),
+            summary[0]["features"]["tracked"]["counters"]
+        );
+    }
+
+    #[tokio::test]
+    async fn close_sends_pending() {
+        let sender = MockEventSender::new();
+        let mut processor = EventProcessor::new(sender.clone(), Config::default());
+
+        let user = User::new("test-user");
+        let flag = FlagBuilder::default().track_events().into_inner();
+        // recorded before starting, buffered in the queue
+        processor.record_evaluation(&flag, &user, &detail());
+        processor.start();
+        processor.close().await;
+
+        assert_eq!(1, sender.events_of_kind("feature").len());
+    }
+
+    #[tokio::test]
+    async fn full_buffer() {
+        let sender = MockEventSender::new();
+        let config = Config {
+            capacity: 2,
+            ..Default::default()
+        };
+        let mut processor = EventProcessor::new(sender.clone(), config);
+
+        let user = User::new("test-user");
+        let flag = FlagBuilder::default().track_events().into_inner();
+        for _ in 0..3 {
+            processor.record_evaluation(&flag, &user, &detail());
+        }
+        processor.start();
+        processor.close().await;
+
+        let events = sender.events();
+        // index and one feature event, summary is not limited
+        assert_eq!(2, events.iter().filter(|e| e["kind"] != "summary").count());
+    }
+
+    #[tokio::test]
+    async fn index_events() {
+        let sender = MockEventSender::new();
+        let mut processor = EventProcessor::new(sender.clone(), Config::default());
+        processor.start();
+
+        let flag = FlagBuilder::default().into_inner();
+        for key in &["user-a", "user-b", "user-a"] {
+            processor.record_evaluation(&flag, &User::new(*key), &detail());
+        }
+        processor.flush().await;
+        // users are remembered across flushes
+        processor.record_evaluation(&flag, &User::new("user-b"), &detail());
+        processor.close().await;
+
+        let events = sender.events_of_kind("index");
+        assert_eq!(2, events.len());
+        assert_eq!("user-a", events[0]["user"]["key"]);
+        assert_eq!("user-b", events[1]["user"]["key"]);
+    }
+
+    #[test]
+    fn forget_user_keys() {
+        let mut outbox = Outbox::new(
+            Config {
+                user_keys_capacity: 2,
+                ..Default::default()
+            },
+            None,
+        );
+        let flag = FlagBuilder::default().into_inner();
+        let evaluate = |outbox: &mut Outbox, key: &str| {
+            let user = EventUser::new(&User::new(key), &Config::default());
+            outbox.add_evaluation(FeatureEvent::new(&flag, user, &detail()), false, None);
+        };
+        let num_index = |outbox: &mut Outbox| {
+            outbox
+                .take()
+                .iter()
+                .filter(|e| matches!(e, Event::Index(_)))
+                .count()
+        };
+
+        evaluate(&mut outbox, "user-a");
+        evaluate(&mut outbox, "user-a");
+        assert_eq!(1, num_index(&mut outbox));
+
+        // periodic flush of the user keys
+        outbox.users.clear();
+        evaluate(&mut outbox, "user-a");
+        assert_eq!(1, num_index(&mut outbox));
+
+        // least recently used key is evicted
+        evaluate(&mut outbox, "user-b");
+        evaluate(&mut outbox, "user-c");
+        evaluate(&mut outbox, "user-a");
+        assert_eq!(3, num_index(&mut outbox));
+    }
+
+    #[tokio::test]
+    async fn inline_users() {
+        let sender = MockEventSender::new();
+        let config = Config {
+            inline_users_in_events: true,
+            ..Default::default()
+        };
+        let mut processor = EventProcessor::new(sender.clone(), config);
+        processor.start();
+
+        let user = User::new("test-user");
+        let flag = FlagBuilder::default().track_events().into_inner();
+        processor.record_evaluation(&flag, &user, &detail());
+        processor.close().await;
+
+        assert!(sender.events_of_kind("index").is_empty());
+        let events = sender.events_of_kind("feature");
+        assert_eq!("test-user", events[0]["user"]["key"]);
+        assert_eq!(None, events[0].get("userKey"));
+    }
+
+    #[tokio::test]
+    async fn identify_and_custom() {
+        let sender = MockEventSender::new();
+        let mut processor = EventProcessor::new(sender.clone(), Config::default());
+        processor.start();
+
+        processor.identify(&User::new("user-a"));
+        processor.track("signup", &User::new("user-a"), None, None);
+        processor.track(
+            "purchase",
+            &User::new("user-b"),
+            Some(json!({ "items": 2 })),
+            Some(9.5),
+        );
+        processor.close().await;
+
+        let identify = sender.events_of_kind("identify");
+        assert_eq!(1, identify.len());
+        assert_eq!("user-a", identify[0]["key"]);
+        assert_eq!("user-a", identify[0]["user"]["key"]);
+
+        // user-a was announced by the identify event
+        let index = sender.events_of_kind("index");
+        assert_eq!(1, index.len());
+        assert_eq!("user-b", index[0]["user"]["key"]);
+
+        let custom = sender.events_of_kind("custom");
+        assert_eq!(2, custom.len());
+        assert_eq!("signup", custom[0]["key"]);
+        assert_eq!("user-a", custom[0]["userKey"]);
+        assert_eq!(None, custom[0].get("data"));
+        assert_eq!("purchase", custom[1]["key"]);
+        assert_eq!(json!({ "items": 2 }), custom[1]["data"]);
+        assert_eq!(9.5, custom[1]["metricValue"]);
+    }
+
+    fn user() -> User<'static> {
+        User::new("test-user")
+            .with_attribute("email", "test@example.com")
+            .with_attribute("ip", "127.0.0.1")
+            .with_attribute("anonymous", true)
+            .with_attribute("team", "platform")
+            .with_attribute("plan", "enterprise")
+    }
+
+    fn serialize(user: &User, config: &Config) -> serde_json::Value {
+        serde_json::to_value(EventUser::new(user, config)).expect("failed to serialize user")
+    }
+
+    #[test]
+    fn user_without_private_attributes() {
+        let json = serialize(&user(), &Config::default());
+        assert_eq!(
+            json,
+            json!({
+                "key": "test-user",
+                "email": "test@example.com",
+                "ip": "127.0.0.1",
+                "anonymous": true,
+                "custom": {
+                    "team": "platform",
+                    "plan": "enterprise"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn globally_private_attributes() {
+        let config = Config {
+            private_attribute_names: vec!["email".to_string(), "plan".to_string()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let json = serialize(&user(), &config);
+        assert_eq!(
+            json,
+            json!({
+                "key": "test-user",
+                "ip": "127.0.0.1",
+                "anonymous": true,
+                "custom": {
+                    "team": "platform"
+                },
+                "privateAttrs": ["email", "plan"]
+            })
+        );
+    }
+
+    #[test]
+    fn all_attributes_private() {
+        let config = Config {
+            all_attributes_private: true,
+            ..Default::default()
+        };
+        let json = serialize(&user(), &config);
+        assert_eq!(
+            json,
+            json!({
+                "key": "test-user",
+                "anonymous": true,
+                "privateAttrs": ["email", "ip", "plan", "team"]
+            })
+        );
+    }
+
+    #[test]
+    fn user_private_attributes() {
+        let user = user()
+            .with_private_attribute("email", "private@example.com")
+            .with_private_attribute("team", "security");
+        let json = serialize(&user, &Config::default());
+        assert_eq!(
+            json,
+            json!({
+                "key": "test-user",
+                "ip": "127.0.0.1",
+                "anonymous": true,
+                "custom": {
+                    "plan": "enterprise"
+                },
+                "privateAttrs": ["email", "team"]
+            })
+        );
+        // private attributes are only hidden in events
+        assert_eq!(
+            Some("private@example.com"),
+            user.attribute("email").as_ref().and_then(|v| v.as_str())
+        );
+    }
+
+    #[test]
+    fn key_is_never_private() {
+        let user = User::new("test-user");
+        let config = Config {
+            all_attributes_private: true,
+            private_attribute_names: vec!["key".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let json = serialize(&user, &config);
+        assert_eq!(json["key"], "test-user");
+    }
+
+    #[tokio::test]
+    async fn feature_event_user_redacted() {
+        let sender = MockEventSender::new();
+        let config = Config {
+            private_attribute_names: vec!["email".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let mut processor = EventProcessor::new(sender.clone(), config);
+        processor.start();
+
+        let flag = FlagBuilder::default().track_events().into_inner();
+        processor.record_evaluation(&flag, &user(), &detail());
+        processor.close().await;
+
+        let events = sender.events_of_kind("index");
+        assert_eq!(1, events.len());
+        assert_eq!(None, events[0]["user"].get("email"));
+        assert_eq!(json!(["email"]), events[0]["user"]["privateAttrs"]);
+    }
+
+    #[tokio::test]
+    async fn debug_events() {
+        let sender = MockEventSender::new();
+        let mut processor = EventProcessor::new(sender.clone(), Config::default());
+        processor.start();
+
+        let user = User::new("test-user");
+        let mut debugged = FlagBuilder::default().with_key("debugged").into_inner();
+        debugged.debug_events_until_date = Some(now_millis() + 3_600_000);
+        processor.record_evaluation(&debugged, &user, &detail());
+        let mut expired = FlagBuilder::default().with_key("expired").into_inner();
+        expired.debug_events_until_date = Some(now_millis() - 1000);
+        processor.record_evaluation(&expired, &user, &detail());
+        processor.close().await;
+
+        let events = sender.events_of_kind("debug");
+        assert_eq!(1, events.len());
+        assert_eq!("debugged", events[0]["key"]);
+        assert_eq!("test-user", events[0]["user"]["key"]);
+        // debugging does not produce feature events
+        assert!(sender.events_of_kind("feature").is_empty());
+    }
+
+    #[tokio::test]
+    async fn experiments() {
+        let sender = MockEventSender::new();
+        let mut processor = EventProcessor::new(sender.clone(), Config::default());
+        processor.start();
+
+        let user = User::new("test-user");
+        let fallthrough = FlagBuilder::default()
+            .with_key("fallthrough")
+            .track_events_fallthrough()
+            .into_inner();
+        processor.record_evaluation(&fallthrough, &user, &detail());
+        let experiment = FlagBuilder::default().with_key("experiment").into_inner();
+        let in_experiment = Detail {
+            reason: Reason::Fallthrough {
+                in_experiment: true,
+            },
+            ..detail()
+        };
+        processor.record_evaluation(&experiment, &user, &in_experiment);
+        let untracked = FlagBuilder::default().with_key("untracked").into_inner();
+        processor.record_evaluation(&untracked, &user, &detail());
+        processor.close().await;
+
+        let events = sender.events_of_kind("feature");
+        assert_eq!(2, events.len());
+        assert_eq!("fallthrough", events[0]["key"]);
+        assert_eq!(json!({ "kind": "FALLTHROUGH" }), events[0]["reason"]);
+        assert_eq!("experiment", events[1]["key"]);
+        assert_eq!(
+            json!({ "kind": "FALLTHROUGH", "inExperiment": true }),
+            events[1]["reason"]
+        );
+    }
+
+    #[test]
+    fn debug_until_server_time() {
+        let mut outbox = Outbox::new(Config::default(), None);
+        let until = now_millis() + 60_000;
+        assert!(outbox.debugging(Some(until)));
+        // server clock is ahead of the local one
+        outbox.last_server_time = Some(until + 1);
+        assert!(!outbox.debugging(Some(until)));
+        assert!(!outbox.debugging(None));
+    }
+
+    #[test]
+    fn parse_server_time() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, server_time(&headers));
+        headers.insert(DATE, "Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap());
+        assert_eq!(Some(784_111_777_000), server_time(&headers));
+    }
+}
@@ -0,0 +1,228 @@
+//! Hooks running around flag evaluations
+//!
+//! Used for tracing spans, metrics, logging or audit trails
+//! without wrapping every evaluation call.
+
+use crate::evaluator::{Detail, Error, User};
+use std::{
+    any::Any,
+    error::Error as StdError,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+use tracing::warn;
+
+/// Error returned by a [Hook], it is logged and doesn't affect the evaluation
+pub type HookError = Box<dyn StdError + Send + Sync>;
+
+/// Data passed from [before_evaluation](Hook::before_evaluation)
+/// to [after_evaluation](Hook::after_evaluation) of the same hook,
+/// e.g. a span or the start time
+pub type SeriesData = Option<Box<dyn Any + Send>>;
+
+/// The evaluation a [Hook] runs for
+pub struct EvaluationContext<'a, 'u> {
+    pub flag_key: &'a str,
+    pub user: &'a User<'u>,
+    /// Value the caller falls back to when the evaluation fails, if any
+    pub default: Option<&'a serde_json::Value>,
+}
+
+/// Code running before and after each evaluation
+///
+/// Hooks don't run for prerequisites or [all flags state](crate::all_flags::AllFlagsState).
+/// Errors and panics of a hook are logged and don't affect the evaluation
+/// or the other hooks.
+pub trait Hook: Send + Sync {
+    /// Name of the hook, used when logging its errors
+    fn name(&self) -> &str;
+
+    /// Called before the flag is evaluated
+    fn before_evaluation(&self, context: &EvaluationContext) -> Result<SeriesData, HookError> {
+        let _ = context;
+        Ok(None)
+    }
+
+    /// Called with the result of the evaluation
+    ///
+    /// `data` is empty when [before_evaluation](Self::before_evaluation) failed.
+    fn after_evaluation(
+        &self,
+        context: &EvaluationContext,
+        data: SeriesData,
+        result: &Result<Detail, Error>,
+    ) -> Result<(), HookError> {
+        let _ = (context, data, result);
+        Ok(())
+    }
+}
+
+/// Registered hooks, run in order before an evaluation
+/// and in reverse order after it
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<Arc<dyn Hook>>);
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a hook, it runs after the ones already registered
+    pub fn add<H: Hook + 'static>(&mut self, hook: H) {
+        self.0.push(Arc::new(hook));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run an evaluation surrounded by the hooks
+    pub fn run<F>(&self, context: &EvaluationContext, evaluate: F) -> Result<Detail, Error>
+    where
+        F: FnOnce() -> Result<Detail, Error>,
+    {
+        if self.0.is_empty() {
+            return evaluate();
+        }
+        let data: Vec<SeriesData> = self
+            .0
+            .iter()
+            .map(|hook| {
+                isolate(hook.as_ref(), "before_evaluation", || {
+                    hook.before_evaluation(context)
+                })
+                .flatten()
+            })
+            .collect();
+        let result = evaluate();
+        for (hook, data) in self.0.iter().zip(data).rev() {
+            isolate(hook.as_ref(), "after_evaluation", || {
+                hook.after_evaluation(context, data, &result)
+            });
+        }
+        result
+    }
+}
+
+/// Call a hook stage, logging errors and panics
+fn isolate<T, F>(hook: &dyn Hook, stage: &str, f: F) -> Option<T>
+where
+    F: FnOnce() -> Result<T, HookError>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(error)) => {
+            warn!(hook = hook.name(), stage, %error, "evaluation hook failed");
+            None
+        }
+        Err(_) => {
+            warn!(hook = hook.name(), stage, "evaluation hook panicked");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EvaluationContext, Hook, HookError, Hooks, SeriesData};
+    use crate::evaluator::{Detail, Error, Reason, User};
+    use std::sync::{Arc, Mutex};
+
+    /// Records every call into a shared log
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        fail_before: bool,
+        panic_after: bool,
+    }
+
+    impl Recorder {
+        fn new(name: &'static str, log: &Arc<Mutex<Vec<String>>>) -> Self {
+            Self {
+                name,
+                log: Arc::clone(log),
+                fail_before: false,
+                panic_after: false,
+            }
+        }
+    }
+
+    impl Hook for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn before_evaluation(&self, context: &EvaluationContext) -> Result<SeriesData, HookError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("before {} {}", self.name, context.flag_key));
+            if self.fail_before {
+                return Err("failed".into());
+            }
+            Ok(Some(Box::new(self.name)))
+        }
+
+        fn after_evaluation(
+            &self,
+            context: &EvaluationContext,
+            data: SeriesData,
+            result: &Result<Detail, Error>,
+        ) -> Result<(), HookError> {
+            let data = data.and_then(|d| d.downcast::<&str>().ok());
+            self.log.lock().unwrap().push(format!(
+                "after {} {} {:?} {:?}",
+                self.name,
+                context.flag_key,
+                data.map(|d| *d),
+                result.as_ref().map(|d| d.value.clone())
+            ));
+            if self.panic_after {
+                panic!("hook panicked");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ordering_and_isolation() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut hooks = Hooks::new();
+        hooks.add(Recorder::new("a", &log));
+        hooks.add(Recorder {
+            fail_before: true,
+            panic_after: true,
+            ..Recorder::new("b", &log)
+        });
+
+        let user = User::new("user");
+        let context = EvaluationContext {
+            flag_key: "flag",
+            user: &user,
+            default: None,
+        };
+        let detail = Detail {
+            value: true.into(),
+            variation_index: 1,
+            reason: Reason::Fallthrough {
+                in_experiment: false,
+            },
+        };
+        let result = hooks.run(&context, || {
+            log.lock().unwrap().push("evaluate".into());
+            Ok(detail.clone())
+        });
+
+        assert_eq!(Ok(detail), result);
+        assert_eq!(
+            vec![
+                "before a flag",
+                "before b flag",
+                "evaluate",
+                "after b flag None Ok(Bool(true))",
+                "after a flag Some(\"a\") Ok(Bool(true))",
+            ],
+            *log.lock().unwrap()
+        );
+    }
+}
@@ -1,22 +1,52 @@
 use self::{
-    consumer::{Consumer, ReadError},
+    all_flags::{AllFlagsOptions, AllFlagsState},
+    consumer::{Consumer, ReadError, ReadHandle},
+    diagnostics::Diagnostics,
     evaluator::Evaluator,
-    source::{Source, SseSource},
+    events::{EventProcessor, HttpEventSender},
+    hooks::Hook,
+    message::InitData,
+    models::{FeatureFlagState, SegmentState},
+    snapshot::Snapshot,
+    source::{NoSource, Source, SseSource},
     store::{MemoryStore, Store},
 };
 use evaluator::Evaluate;
+use futures::future;
+use hmac::{Hmac, Mac, NewMac};
 use http::header::InvalidHeaderValue;
-use models::FeatureFlagState;
-use std::{collections::HashMap, error::Error as StdError, fmt, sync::Arc};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::oneshot, time};
 
+pub mod all_flags;
+pub mod compiled;
 pub mod consumer;
+pub mod diagnostics;
 pub mod evaluator;
+pub mod events;
+pub mod hooks;
 pub mod message;
 pub mod models;
+mod operators;
+pub mod persistent;
+pub mod snapshot;
 pub mod source;
 pub mod store;
 #[cfg(test)]
 mod test_utils;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod validation;
+
+/// Interval for checking whether the store got initialized in daemon mode
+const DAEMON_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, thiserror::Error)]
 pub enum StartError<CE>
@@ -28,6 +58,9 @@ where
 
     #[error("Failed to start reading from source: {0}")]
     Start(#[from] ReadError<CE>),
+
+    #[error("Timed out waiting for initial data after {0:?}")]
+    Timeout(Duration),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -40,10 +73,20 @@ pub enum CreateError {
 /// variation values for flags.
 ///
 /// Glue code on top of the smaller building blocks.
+///
+/// Background tasks are stopped when the client is dropped.
+/// Use [close](Self::close) to shut down gracefully instead.
 pub struct DefaultClient<ST, SRC> {
     store: Arc<ST>,
-    evaluator: Evaluator<Arc<ST>>,
     source: Option<SRC>,
+    /// Taken by [close](Self::close)
+    reader: Mutex<Option<ReadHandle>>,
+    events: Option<EventProcessor>,
+    evaluator: Evaluator<Arc<ST>>,
+    token: Option<String>,
+    /// Flag data is provided by the store instead of a source,
+    /// taken by [start](Self::start) like the source
+    daemon: bool,
 }
 
 impl DefaultClient<MemoryStore, SseSource> {
@@ -51,7 +94,57 @@ impl DefaultClient<MemoryStore, SseSource> {
     pub fn with_token(token: String) -> Result<Self, CreateError> {
         let source = SseSource::new(&token);
         let store = Arc::new(MemoryStore::new());
-        Ok(Self::new(store, source))
+        let sender = HttpEventSender::new(&token).map_err(CreateError::InvalidToken)?;
+        let diagnostics = Arc::new(Diagnostics::new(&token));
+        let events = EventProcessor::with_diagnostics(sender, Default::default(), diagnostics);
+        let mut client = Self::new(store, source).with_events(events);
+        client.token = Some(token);
+        Ok(client)
+    }
+}
+
+impl<SRC> DefaultClient<MemoryStore, SRC> {
+    /// Seed the store with a snapshot
+    ///
+    /// Call this before [start](Self::start), so flags can be evaluated
+    /// before the stream connects. The client stays uninitialized until
+    /// the initial data from the stream arrives and replaces the snapshot.
+    ///
+    /// Does nothing once the store is initialized, the snapshot is older
+    /// than the data from the stream.
+    ///
+    /// Only available for the [MemoryStore], a shared persistent store is
+    /// never overwritten with data that may be stale.
+    pub fn import_snapshot(&self, snapshot: Snapshot) {
+        if self.store.initialized() {
+            return;
+        }
+        self.store.seed(InitData {
+            flags: snapshot.flags,
+            segments: snapshot.segments,
+        });
+    }
+}
+
+impl<ST: Store> DefaultClient<ST, NoSource> {
+    /// Make a client reading from a store only, without connecting to LaunchDarkly
+    ///
+    /// This is called daemon mode. The store is expected to be populated
+    /// by another process, e.g. the Relay Proxy writing to a shared
+    /// [persistent store](persistent::PersistentDataStore).
+    /// The client is initialized once the store is.
+    pub fn daemon<STA: Into<Arc<ST>>>(store: STA) -> Self {
+        let store = store.into();
+        let evaluator = Evaluator::new(Arc::clone(&store));
+        Self {
+            store,
+            source: None,
+            reader: Mutex::new(None),
+            events: None,
+            evaluator,
+            token: None,
+            daemon: true,
+        }
     }
 }
 
@@ -64,12 +157,28 @@ where
         let store = store.into();
         let evaluator = Evaluator::new(Arc::clone(&store));
         Self {
-            evaluator,
             store,
             source: Some(source),
+            reader: Mutex::new(None),
+            events: None,
+            evaluator,
+            token: None,
+            daemon: false,
         }
     }
 
+    /// Send analytics events through an [EventProcessor]
+    pub fn with_events(mut self, events: EventProcessor) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Run a [Hook] around every evaluation, after the hooks added before
+    pub fn with_hook<H: Hook + 'static>(mut self, hook: H) -> Self {
+        self.evaluator = self.evaluator.with_hook(hook);
+        self
+    }
+
     /// Start consuming data in the client
     ///
     /// Future resolves once the initial data has been read.
@@ -83,17 +192,301 @@ where
         SRC::Stream: Unpin + Send,
         SRC::Error: StdError + Send,
     {
-        let source = self.source.take().ok_or(StartError::AlreadyStarted)?;
+        let source = match self.source.take() {
+            Some(source) => source,
+            None if self.daemon => return self.start_daemon().await,
+            None => return Err(StartError::AlreadyStarted),
+        };
+        if let Some(events) = &mut self.events {
+            events.start();
+        }
         let store = Arc::clone(&self.store);
-        store.read_from(source).await.map_err(Into::into)
+        let diagnostics = self.events.as_ref().and_then(|e| e.diagnostics()).cloned();
+        let (reader, init) = match diagnostics {
+            Some(diagnostics) => store.read_from_with_diagnostics(source, diagnostics),
+            None => store.read_from_with_handle(source),
+        };
+        *self.reader.get_mut().unwrap() = Some(reader);
+        init.await.map_err(Into::into)
+    }
+
+    /// Wait until the store was initialized by another process
+    ///
+    /// The store is polled in a background task, stopped by [close](Self::close).
+    async fn start_daemon<E>(&mut self) -> Result<(), StartError<E>>
+    where
+        ST: Send + Sync + 'static,
+        E: fmt::Debug + Clone + StdError + 'static,
+    {
+        self.daemon = false;
+        if let Some(events) = &mut self.events {
+            events.start();
+        }
+        let store = Arc::clone(&self.store);
+        let (init_tx, init_rx) = oneshot::channel();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut poll = time::interval(DAEMON_POLL_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = poll.tick() => {}
+                    _ = &mut shutdown_rx => return,
+                }
+                if store.initialized() {
+                    let _ = init_tx.send(());
+                    return;
+                }
+            }
+        });
+        *self.reader.get_mut().unwrap() = Some(ReadHandle::new(shutdown_tx, task));
+        init_rx
+            .await
+            .map_err(|_| StartError::Start(ReadError::TaskDropped))
+    }
+
+    /// Start consuming data in the client, waiting at most `timeout`
+    /// for the initial data.
+    ///
+    /// Resolves with [StartError::Timeout] when the data did not arrive in time.
+    /// Reading continues in the background either way, use [initialized](Self::initialized)
+    /// to check whether evaluations are based on actual flag data yet.
+    pub async fn start_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(), StartError<ST::Error>>
+    where
+        ST: Consumer<SRC> + Send + Sync + 'static,
+        ST::Error: StdError + Clone + Send + Sync,
+        SRC: Source + Send + 'static,
+        SRC::Stream: Unpin + Send,
+        SRC::Error: StdError + Send,
+    {
+        time::timeout(timeout, self.start())
+            .await
+            .map_err(|_| StartError::Timeout(timeout))?
+    }
+
+    /// Whether the client has received its initial data
+    pub fn initialized(&self) -> bool {
+        self.store.initialized()
+    }
+
+    /// Evaluate all flags for a user
+    ///
+    /// Meant for bootstrapping client-side SDKs. The result
+    /// is marked as invalid while the client is not initialized.
+    pub fn all_flags_state(
+        &self,
+        user: &evaluator::User,
+        options: AllFlagsOptions,
+    ) -> AllFlagsState {
+        if !self.initialized() {
+            return AllFlagsState::invalid();
+        }
+        AllFlagsState::evaluate(&self.store, user, &options)
+    }
+
+    /// Compute the hash for a user required by the secure mode
+    /// of client-side SDKs
+    ///
+    /// Only available when the client was created [with a token](DefaultClient::with_token).
+    pub fn secure_mode_hash(&self, user: &evaluator::User) -> Option<String> {
+        self.token
+            .as_ref()
+            .map(|token| secure_mode_hash(token, user.key()))
+    }
+
+    /// Evaluate a flag, explaining how the variation was determined
+    pub fn evaluate_detail(
+        &self,
+        flag: &str,
+        user: &evaluator::User,
+    ) -> Result<evaluator::Detail, evaluator::Error> {
+        self.evaluate_with_default(flag, user, None, |_| true)
+    }
+
+    /// Evaluate a flag, falling back to `default` when the evaluation fails
+    pub fn variation(
+        &self,
+        flag: &str,
+        user: &evaluator::User,
+        default: serde_json::Value,
+    ) -> serde_json::Value {
+        self.evaluate_with_default(flag, user, Some(&default), |_| true)
+            .map(|detail| detail.value)
+            .unwrap_or(default)
+    }
+
+    /// Evaluate a flag expecting a type of value, explaining how the variation was determined
+    ///
+    /// A value rejected by `check`, e.g. [is_boolean](serde_json::Value::is_boolean),
+    /// fails the evaluation with [InvalidVariationType](evaluator::Error::InvalidVariationType).
+    /// Hooks and analytics events see the `default` the application falls back to.
+    pub fn variation_detail<C>(
+        &self,
+        flag: &str,
+        user: &evaluator::User,
+        default: &serde_json::Value,
+        check: C,
+    ) -> Result<evaluator::Detail, evaluator::Error>
+    where
+        C: Fn(&serde_json::Value) -> bool,
+    {
+        self.evaluate_with_default(flag, user, Some(default), check)
+    }
+
+    /// Evaluate a single flag, telling the hooks and events about the default
+    fn evaluate_with_default<C>(
+        &self,
+        key: &str,
+        user: &evaluator::User,
+        default: Option<&serde_json::Value>,
+        check: C,
+    ) -> Result<evaluator::Detail, evaluator::Error>
+    where
+        C: Fn(&serde_json::Value) -> bool,
+    {
+        self.evaluator
+            .evaluate_observed(key, user, default, check, |flag, result| {
+                if let Some(events) = &self.events {
+                    events.record_evaluation_with_default(flag, user, result.as_ref(), default);
+                }
+            })
+    }
+
+    /// Evaluate several flags for a user at once
+    ///
+    /// Flags are read from one snapshot of the store and prerequisites shared
+    /// by several flags are evaluated once, see [Batch](evaluator::Batch).
+    pub fn evaluate_many<'k, I>(
+        &self,
+        flags: I,
+        user: &evaluator::User,
+    ) -> HashMap<String, Result<evaluator::Detail, evaluator::Error>>
+    where
+        I: IntoIterator<Item = &'k str>,
+    {
+        self.evaluator
+            .evaluate_many_observed(flags, user, |flag, result| self.record(flag, user, result))
+    }
+
+    /// Evaluate all flags for a user at once
+    ///
+    /// Unlike [all_flags_state](Self::all_flags_state), this records
+    /// analytics events like any other evaluation.
+    pub fn evaluate_all(
+        &self,
+        user: &evaluator::User,
+    ) -> HashMap<String, Result<evaluator::Detail, evaluator::Error>> {
+        self.evaluator
+            .evaluate_all_observed(user, |flag, result| self.record(flag, user, result))
+    }
+
+    /// Record the analytics event of an evaluation
+    fn record(
+        &self,
+        flag: &FeatureFlagState,
+        user: &evaluator::User,
+        result: &Result<evaluator::Detail, evaluator::Error>,
+    ) {
+        if let (Some(events), Ok(detail)) = (&self.events, result) {
+            events.record_evaluation(flag, user, detail);
+        }
+    }
+
+    /// Report a user to LaunchDarkly, e.g. after logging in
+    pub fn identify(&self, user: &evaluator::User) {
+        if let Some(events) = &self.events {
+            events.identify(user);
+        }
+    }
+
+    /// Track a custom event for a user, e.g. a conversion in an experiment
+    ///
+    /// The metric value is used by numeric metrics.
+    pub fn track(
+        &self,
+        key: &str,
+        user: &evaluator::User,
+        data: Option<serde_json::Value>,
+        metric_value: Option<f64>,
+    ) {
+        if let Some(events) = &self.events {
+            events.track(key, user, data, metric_value);
+        }
+    }
+
+    /// Send all pending analytics events
+    pub async fn flush(&self) {
+        if let Some(events) = &self.events {
+            events.flush().await;
+        }
+    }
+
+    /// Shut down the client
+    ///
+    /// Stops reading from the source, or polling the store in daemon mode,
+    /// and sends all pending
+    /// analytics events. Resolves once both are done.
+    ///
+    /// Evaluations keep working on the last known flags,
+    /// but their analytics events are dropped.
+    pub async fn close(&self) {
+        let reader = self.reader.lock().unwrap().take();
+        future::join(
+            async move {
+                if let Some(reader) = reader {
+                    reader.close().await;
+                }
+            },
+            async {
+                if let Some(events) = &self.events {
+                    events.close().await;
+                }
+            },
+        )
+        .await;
+    }
+
+    /// Problems found in the flags of the underlying [Store], by flag key
+    ///
+    /// Meant for alerting on malformed flag configurations.
+    pub fn flag_diagnostics(&self) -> HashMap<String, Vec<validation::Diagnostic>> {
+        self.store.diagnostics()
     }
 
     /// Export the feature flagging data from the underlying [Store]
+    ///
+    /// Clones every flag, use [Store::export_all] on the store
+    /// to share them instead.
     pub fn export(&self) -> HashMap<String, FeatureFlagState> {
-        self.store.export_all()
+        self.store
+            .export_all()
+            .into_iter()
+            .map(|(key, flag)| (key, FeatureFlagState::clone(&flag)))
+            .collect()
+    }
+
+    /// Snapshot of the current flag data, e.g. to [save](Snapshot::save) for a warm start
+    pub fn snapshot(&self) -> Snapshot {
+        let segments = self
+            .store
+            .export_segments()
+            .into_iter()
+            .map(|(key, segment)| (key, SegmentState::clone(&segment)))
+            .collect();
+        Snapshot::new(self.export(), segments)
     }
 }
 
+/// HMAC-SHA256 of the user key, signed with the SDK token
+fn secure_mode_hash(token: &str, user_key: &str) -> String {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).unwrap();
+    mac.update(user_key.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
 impl<ST, SRC> Evaluate for DefaultClient<ST, SRC>
 where
     ST: Store,
@@ -103,17 +496,32 @@ where
         flag: &str,
         user: &evaluator::User,
     ) -> Result<serde_json::Value, evaluator::Error> {
-        self.evaluator.evaluate(flag, user)
+        self.evaluate_detail(flag, user).map(|detail| detail.value)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        evaluator::{Evaluate, User},
-        test_utils::{FlagBuilder, MockStore, NullSource},
-        DefaultClient,
+        diagnostics::{DiagnosticEvent, Diagnostics},
+        evaluator::{self, Detail, Evaluate, User},
+        events::{Config, EventProcessor},
+        hooks::{EvaluationContext, Hook, HookError, SeriesData},
+        models::{FeatureFlagState, SegmentState},
+        persistent::{AllData, CachingStore, DataKind, PersistentDataStore, SerializedItem},
+        secure_mode_hash,
+        store::MemoryStore,
+        test_utils::{
+            FlagBuilder, InitSource, MockEventSender, MockPersistentStore, MockStore, NullSource,
+        },
+        DefaultClient, StartError,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
     };
+    use tokio::time;
 
     #[tokio::test]
     async fn smoke() {
@@ -144,4 +552,315 @@ mod tests {
             assert!(!result);
         }
     }
+
+    /// Default and value of an evaluation
+    type Seen = (Option<serde_json::Value>, Option<serde_json::Value>);
+
+    /// Remembers the defaults and values of the evaluations
+    struct DefaultsHook(Arc<Mutex<Vec<Seen>>>);
+
+    impl Hook for DefaultsHook {
+        fn name(&self) -> &str {
+            "defaults"
+        }
+
+        fn after_evaluation(
+            &self,
+            context: &EvaluationContext,
+            _data: SeriesData,
+            result: &Result<Detail, evaluator::Error>,
+        ) -> Result<(), HookError> {
+            let value = result.as_ref().ok().map(|detail| detail.value.clone());
+            self.0
+                .lock()
+                .unwrap()
+                .push((context.default.cloned(), value));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn variation_hooks() {
+        let mut store = MockStore::new();
+        store.add(FlagBuilder::default().with_key("flag").into_inner());
+        let evaluations = Arc::new(Mutex::new(Vec::new()));
+        let client =
+            DefaultClient::new(store, NullSource).with_hook(DefaultsHook(Arc::clone(&evaluations)));
+
+        let user = User::new("user");
+        assert_eq!(
+            serde_json::Value::from(false),
+            client.variation("flag", &user, true.into())
+        );
+        assert_eq!(
+            serde_json::Value::from("default"),
+            client.variation("missing", &user, "default".into())
+        );
+        assert!(client.evaluate_detail("flag", &user).is_ok());
+        assert_eq!(
+            vec![
+                (Some(true.into()), Some(false.into())),
+                (Some("default".into()), None),
+                (None, Some(false.into())),
+            ],
+            *evaluations.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn variation_detail_wrong_type() {
+        let flag = FlagBuilder::default()
+            .with_key("flag")
+            .track_events()
+            .into_inner();
+        let sender = MockEventSender::new();
+        let mut events = EventProcessor::new(sender.clone(), Config::default());
+        events.start();
+        let mut store = MockStore::new();
+        store.add(flag);
+        let evaluations = Arc::new(Mutex::new(Vec::new()));
+        let client = DefaultClient::new(store, NullSource)
+            .with_events(events)
+            .with_hook(DefaultsHook(Arc::clone(&evaluations)));
+
+        let user = User::new("user");
+        let default = serde_json::Value::from("default");
+        let result = client.variation_detail("flag", &user, &default, |v| v.is_string());
+        assert!(matches!(
+            result,
+            Err(evaluator::Error::InvalidVariationType)
+        ));
+        assert_eq!(
+            vec![(Some(default.clone()), None)],
+            *evaluations.lock().unwrap()
+        );
+
+        client.flush().await;
+        let events = sender.events_of_kind("feature");
+        assert_eq!(1, events.len());
+        assert_eq!(default, events[0]["value"]);
+        assert_eq!(default, events[0]["default"]);
+        assert_eq!(None, events[0].get("variation"));
+    }
+
+    #[tokio::test]
+    async fn start_timeout() {
+        let mut client = DefaultClient::new(MemoryStore::new(), NullSource);
+        let result = client.start_with_timeout(Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(StartError::Timeout(_))));
+        assert!(!client.initialized());
+
+        // the source was consumed by the first attempt
+        let result = client.start_with_timeout(Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(StartError::AlreadyStarted)));
+    }
+
+    #[tokio::test]
+    async fn start_with_timeout_initialized() {
+        let mut client = DefaultClient::new(MemoryStore::new(), InitSource::default());
+        assert!(!client.initialized());
+        client
+            .start_with_timeout(Duration::from_secs(5))
+            .await
+            .expect("failed to start");
+        assert!(client.initialized());
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let flag = FlagBuilder::default()
+            .with_key("tracked_flag")
+            .track_events()
+            .into_inner();
+        let source = InitSource(vec![flag]);
+
+        let sender = MockEventSender::new();
+        let events = EventProcessor::new(sender.clone(), Config::default());
+        let mut client = DefaultClient::new(MemoryStore::new(), source).with_events(events);
+        client.start().await.expect("failed to start");
+
+        let user = User::new("test-user");
+        client
+            .bool_variation("tracked_flag", &user)
+            .expect("evaluation failed");
+
+        // completes although the source never ends
+        client.close().await;
+        assert_eq!(1, sender.events_of_kind("feature").len());
+    }
+
+    #[tokio::test]
+    async fn diagnostics() {
+        let sender = MockEventSender::new();
+        let diagnostics = Arc::new(Diagnostics::new("sdk-key"));
+        let events = EventProcessor::with_diagnostics(
+            sender.clone(),
+            Config::default(),
+            diagnostics.clone(),
+        );
+        let mut client =
+            DefaultClient::new(MemoryStore::new(), InitSource::default()).with_events(events);
+        client.start().await.expect("failed to start");
+        client.close().await;
+
+        assert_eq!(1, sender.events_of_kind("diagnostic-init").len());
+        let stats = match diagnostics.stats_event(0) {
+            DiagnosticEvent::Stats(stats) => stats,
+            _ => panic!("expected stats event"),
+        };
+        assert_eq!(1, stats.stream_inits.len());
+        assert!(!stats.stream_inits[0].failed);
+    }
+
+    #[tokio::test]
+    async fn diagnostic_opt_out() {
+        let sender = MockEventSender::new();
+        let config = Config {
+            diagnostic_opt_out: true,
+            ..Default::default()
+        };
+        let mut events = EventProcessor::with_diagnostics(
+            sender.clone(),
+            config,
+            Arc::new(Diagnostics::new("")),
+        );
+        assert!(events.diagnostics().is_none());
+        events.start();
+        events.close().await;
+        assert!(sender.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn daemon() {
+        let store = Arc::new(CachingStore::new(
+            MockPersistentStore::default(),
+            Duration::from_secs(60),
+        ));
+        let mut client = DefaultClient::<CachingStore<_>, _>::daemon(Arc::clone(&store));
+        let result = client.start_with_timeout(Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(StartError::Timeout(_))));
+        assert!(!client.initialized());
+
+        // populated by another process, e.g. the relay proxy
+        let flag = FlagBuilder::default()
+            .on()
+            .with_key("daemon_flag")
+            .add_target(1, "daemon-user")
+            .into_inner();
+        let item = SerializedItem {
+            version: 1,
+            deleted: false,
+            data: serde_json::to_string(&flag).unwrap(),
+        };
+        let mut data = AllData::new();
+        data.insert(
+            DataKind::Features,
+            vec![("daemon_flag".to_string(), item)]
+                .into_iter()
+                .collect(),
+        );
+        data.insert(DataKind::Segments, HashMap::new());
+        store.inner().init(data).unwrap();
+
+        // the store is still polled after the timeout
+        time::timeout(Duration::from_secs(5), async {
+            while !client.initialized() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("not initialized in time");
+        let result = client
+            .bool_variation("daemon_flag", &User::new("daemon-user"))
+            .expect("evaluation failed");
+        assert!(result);
+
+        let result = client.start().await;
+        assert!(matches!(result, Err(StartError::AlreadyStarted)));
+    }
+
+    #[tokio::test]
+    async fn daemon_close() {
+        let store = Arc::new(CachingStore::new(
+            MockPersistentStore::default(),
+            Duration::from_secs(60),
+        ));
+        let mut client = DefaultClient::<CachingStore<_>, _>::daemon(Arc::clone(&store));
+        let references = Arc::strong_count(&store);
+        let result = client.start_with_timeout(Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(StartError::Timeout(_))));
+        // the polling task holds the store
+        assert_eq!(references + 1, Arc::strong_count(&store));
+
+        // stops polling the store that is never initialized
+        time::timeout(Duration::from_secs(5), client.close())
+            .await
+            .expect("close timed out");
+        assert_eq!(references, Arc::strong_count(&store));
+    }
+
+    #[tokio::test]
+    async fn export_snapshot() {
+        let flag = FlagBuilder::default()
+            .with_key("snapshot_flag")
+            .add_target(1, "test-user")
+            .into_inner();
+        let expected = serde_json::to_value(&flag).unwrap();
+        let mut client = DefaultClient::new(MemoryStore::new(), InitSource(vec![flag]));
+        client.start().await.expect("failed to start");
+
+        let snapshot = serde_json::to_string(&client.export()).expect("failed to serialize");
+        let flags: HashMap<String, FeatureFlagState> =
+            serde_json::from_str(&snapshot).expect("failed to parse snapshot");
+        assert_eq!(
+            expected,
+            serde_json::to_value(&flags["snapshot_flag"]).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn warm_start() {
+        let flag = FlagBuilder::default()
+            .with_key("old_flag")
+            .add_target(1, "test-user")
+            .into_inner();
+        let mut previous = DefaultClient::new(MemoryStore::new(), InitSource(vec![flag]));
+        previous.start().await.expect("failed to start");
+        let mut snapshot = previous.snapshot();
+        let segment: SegmentState =
+            serde_json::from_str(include_str!("../tests/fixtures/segment.json")).unwrap();
+        snapshot.segments.insert(segment.key.clone(), segment);
+
+        let flag = FlagBuilder::default().with_key("new_flag").into_inner();
+        let mut client = DefaultClient::new(MemoryStore::new(), InitSource(vec![flag]));
+        client.import_snapshot(snapshot.clone());
+        // evaluates before the stream connects
+        let user = User::new("test-user");
+        assert!(client.bool_variation("old_flag", &user).unwrap());
+        assert!(!client.initialized());
+        assert!(client.snapshot().segments.contains_key("beta-users"));
+
+        // replaced by the data from the stream
+        client.start().await.expect("failed to start");
+        assert!(client.bool_variation("old_flag", &user).is_err());
+        assert!(client.bool_variation("new_flag", &user).is_ok());
+        assert!(client.snapshot().segments.is_empty());
+
+        // a stale snapshot doesn't replace the data from the stream
+        client.import_snapshot(snapshot);
+        assert!(client.bool_variation("old_flag", &user).is_err());
+        assert!(client.bool_variation("new_flag", &user).is_ok());
+    }
+
+    #[test]
+    fn secure_mode() {
+        // vector used in the tests of the other LaunchDarkly server SDKs
+        assert_eq!(
+            "aa747c502a898200f9e4fa21bac68136f886a0e27aec70ba06daf2e2a5cb5597",
+            secure_mode_hash("secret", "Message")
+        );
+
+        let client = DefaultClient::new(MockStore::new(), NullSource);
+        assert_eq!(None, client.secure_mode_hash(&User::new("Message")));
+    }
 }
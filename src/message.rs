@@ -1,6 +1,6 @@
-use crate::models::FeatureFlagState;
+use crate::models::{FeatureFlagState, SegmentState};
 use eventsource_client::Event;
-use serde::Deserialize;
+use serde::{ser, Deserialize, Serialize, Serializer};
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
@@ -27,6 +27,9 @@ pub enum MessageParseError {
 }
 
 /// Parsed message from the stream
+///
+/// Serializes to the payload of the SSE event named by [Message::event_type],
+/// so messages can be streamed to downstream services.
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Message {
@@ -36,6 +39,83 @@ pub enum Message {
     Unknown,
 }
 
+impl Message {
+    /// Name of the SSE event carrying this message
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Self::Put(_) => "put",
+            Self::Patch(_) => "patch",
+            Self::Delete(_) => "delete",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Payload of a [Message] for serializing
+#[derive(Serialize)]
+struct MessagePayloadRef<'a> {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<PayloadData<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum PayloadData<'a> {
+    Put {
+        flags: &'a HashMap<String, FeatureFlagState>,
+        segments: &'a HashMap<String, SegmentState>,
+    },
+    Flag(&'a FeatureFlagState),
+    Segment(&'a SegmentState),
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let payload = match self {
+            Self::Put(InitData { flags, segments }) => MessagePayloadRef {
+                path: "/".into(),
+                data: Some(PayloadData::Put { flags, segments }),
+                version: None,
+            },
+            Self::Patch(Update::Flag {
+                name,
+                data,
+                version,
+            })
+            | Self::Delete(Update::Flag {
+                name,
+                data,
+                version,
+            }) => MessagePayloadRef {
+                path: format!("/flags/{}", name),
+                data: data.as_ref().map(PayloadData::Flag),
+                version: *version,
+            },
+            Self::Patch(Update::Segment {
+                name,
+                data,
+                version,
+            })
+            | Self::Delete(Update::Segment {
+                name,
+                data,
+                version,
+            }) => MessagePayloadRef {
+                path: format!("/segments/{}", name),
+                data: data.as_ref().map(PayloadData::Segment),
+                version: *version,
+            },
+            Self::Patch(Update::Unknown) | Self::Delete(Update::Unknown) | Self::Unknown => {
+                return Err(ser::Error::custom("unknown messages can't be serialized"));
+            }
+        };
+        payload.serialize(serializer)
+    }
+}
+
 impl TryFrom<Event> for Message {
     type Error = MessageParseError;
 
@@ -57,7 +137,11 @@ impl TryFrom<Event> for Message {
                 // parse into specific struct
                 let flag_config: InitData =
                     serde_json::from_value(data).map_err(MessageParseError::ParsePut)?;
-                trace!(num_flags = flag_config.flags.len(), "parsed init data");
+                trace!(
+                    num_flags = flag_config.flags.len(),
+                    num_segments = flag_config.segments.len(),
+                    "parsed init data"
+                );
                 Ok(Self::Put(flag_config))
             }
             // change or delete a single record
@@ -81,12 +165,23 @@ impl TryFrom<Event> for Message {
 }
 
 /// Data used to initially populate a [Store](crate::store::Store)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct InitData {
-    // todo: store user segments
-    //pub segments: models::user_segments::UserSegments,
     /// Config for all flags
     pub flags: HashMap<String, FeatureFlagState>,
+    /// All user segments
+    #[serde(default)]
+    pub segments: HashMap<String, SegmentState>,
+}
+
+impl InitData {
+    /// Init data without segments
+    pub fn with_flags(flags: HashMap<String, FeatureFlagState>) -> Self {
+        Self {
+            flags,
+            segments: HashMap::new(),
+        }
+    }
 }
 
 /// Update Payload (parsed from json)
@@ -108,6 +203,9 @@ pub enum FromPatchDataError {
     #[error("Missing flag name")]
     MissingFlagName,
 
+    #[error("Missing segment name")]
+    MissingSegmentName,
+
     #[error("Failed to read flag payload")]
     InvalidPayload(#[from] serde_json::Error),
 }
@@ -125,6 +223,13 @@ pub enum Update {
         data: Option<FeatureFlagState>,
         version: Option<u64>,
     },
+    /// a user segment changed
+    Segment {
+        /// key of the segment
+        name: String,
+        data: Option<SegmentState>,
+        version: Option<u64>,
+    },
     /// any type of record we haven't implemented
     Unknown,
 }
@@ -158,8 +263,113 @@ impl TryFrom<MessagePayload> for Update {
                     version: pl.version,
                 })
             }
+            // update for user segments
+            "segments" => {
+                let name = segments
+                    .next()
+                    .ok_or(FromPatchDataError::MissingSegmentName)?
+                    .into();
+                let data = pl.data.map(serde_json::from_value).transpose()?;
+                Ok(Self::Segment {
+                    name,
+                    data,
+                    version: pl.version,
+                })
+            }
             // path we don't handle yet
             _ => Ok(Self::Unknown),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, Update};
+    use eventsource_client::Event;
+    use serde_json::{json, Value};
+    use std::convert::TryFrom;
+
+    fn event(event_type: &str, payload: &Value) -> Event {
+        let mut event = Event::new();
+        event.event_type = event_type.into();
+        event.set_field("data", payload.to_string().as_bytes());
+        event
+    }
+
+    /// Flag as sent by LaunchDarkly
+    fn flag() -> Value {
+        let mut flag: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/flag.json")).unwrap();
+        flag["prerequisites"] = json!([{ "key": "other-flag", "variation": 0 }]);
+        flag
+    }
+
+    /// User segment as sent by LaunchDarkly
+    fn segment() -> Value {
+        serde_json::from_str(include_str!("../tests/fixtures/segment.json")).unwrap()
+    }
+
+    fn round_trip(event_type: &str, payload: Value) {
+        let msg = Message::try_from(event(event_type, &payload)).expect("failed to parse");
+        assert_eq!(event_type, msg.event_type());
+        assert_eq!(
+            payload,
+            serde_json::to_value(&msg).expect("failed to serialize")
+        );
+    }
+
+    #[test]
+    fn put() {
+        round_trip(
+            "put",
+            json!({
+                "path": "/",
+                "data": {
+                    "flags": { "my-flag": flag() },
+                    "segments": { "beta-users": segment() }
+                }
+            }),
+        );
+    }
+
+    #[test]
+    fn unknown_flag_fields() {
+        let msg = Message::try_from(event(
+            "patch",
+            &json!({ "path": "/flags/my-flag", "data": flag() }),
+        ))
+        .expect("failed to parse");
+        match msg {
+            Message::Patch(Update::Flag {
+                data: Some(flag), ..
+            }) => assert_eq!(
+                json!({ "sel": "7c0e1a9f4b2d4e8c9a3f6b5d2e1c0a98" }),
+                Value::Object(flag.extra)
+            ),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn patch() {
+        round_trip("patch", json!({ "path": "/flags/my-flag", "data": flag() }));
+        round_trip(
+            "patch",
+            json!({ "path": "/segments/beta-users", "data": segment() }),
+        );
+    }
+
+    #[test]
+    fn delete() {
+        round_trip("delete", json!({ "path": "/flags/my-flag", "version": 8 }));
+        round_trip(
+            "delete",
+            json!({ "path": "/segments/beta-users", "version": 4 }),
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        assert!(serde_json::to_string(&Message::Unknown).is_err());
+    }
+}
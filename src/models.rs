@@ -14,18 +14,23 @@ use self::{
     client_side_availability::ClientSideAvailability, fallthrough::Fallthrough,
     prerequisite::Prerequisite, rule::Rule, target::Target,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Special struct for deserializing SSE updates.
 ///
 /// This struct is not present in the OpenAPI spec,
 /// but uses some of the generated models for its fields.
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct FeatureFlagState {
     #[serde(rename = "clientSide")]
     pub client_side: bool,
     #[serde(rename = "clientSideAvailability")]
     pub client_side_availability: ClientSideAvailability,
+    #[serde(
+        rename = "debugEventsUntilDate",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub debug_events_until_date: Option<u64>,
     pub deleted: bool,
     pub fallthrough: Fallthrough,
     pub key: String,
@@ -42,4 +47,21 @@ pub struct FeatureFlagState {
     pub track_events_fallthrough: bool,
     pub variations: Vec<serde_json::Value>,
     pub version: u64,
+    /// Fields not known to the SDK, kept so the flag re-serializes as received
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Special struct for deserializing user segments of SSE updates.
+///
+/// Segments are not evaluated yet. Only the fields needed for storing
+/// them are parsed, everything else is kept as sent by LaunchDarkly.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct SegmentState {
+    pub key: String,
+    pub version: u64,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(flatten)]
+    pub data: serde_json::Map<String, serde_json::Value>,
 }
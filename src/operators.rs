@@ -0,0 +1,232 @@
+//! Clause operators used by targeting rules
+//!
+//! https://docs.launchdarkly.com/sdk/concepts/flag-evaluation-rules#operators
+
+use chrono::DateTime;
+use regex::Regex;
+use semver::{BuildMetadata, Version};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// Clause operator with its values parsed ahead of evaluation
+///
+/// Clause values of the wrong type never match. Rules with an unknown operator,
+/// like `segmentMatch`, fail the evaluation instead of being skipped.
+#[derive(Debug, Clone)]
+pub(crate) enum Operator {
+    In {
+        strings: HashSet<String>,
+        numbers: Vec<f64>,
+        bools: Vec<bool>,
+    },
+    StartsWith(Vec<String>),
+    EndsWith(Vec<String>),
+    Contains(Vec<String>),
+    Matches(Vec<Regex>),
+    LessThan(Vec<f64>),
+    LessThanOrEqual(Vec<f64>),
+    GreaterThan(Vec<f64>),
+    GreaterThanOrEqual(Vec<f64>),
+    Before(Vec<f64>),
+    After(Vec<f64>),
+    SemVerEqual(Vec<Version>),
+    SemVerLessThan(Vec<Version>),
+    SemVerGreaterThan(Vec<Version>),
+    Unknown,
+}
+
+impl Operator {
+    /// Parse the clause values for an operator
+    pub(crate) fn new(op: &str, values: &[Value]) -> Self {
+        let strings = || values.iter().filter_map(Value::as_str).map(String::from);
+        let numbers = || values.iter().filter_map(Value::as_f64).collect();
+        let times = || values.iter().filter_map(time).collect();
+        let semvers = || values.iter().filter_map(semver).collect();
+        match op {
+            "in" => Self::In {
+                strings: strings().collect(),
+                numbers: numbers(),
+                bools: values.iter().filter_map(Value::as_bool).collect(),
+            },
+            "startsWith" => Self::StartsWith(strings().collect()),
+            "endsWith" => Self::EndsWith(strings().collect()),
+            "contains" => Self::Contains(strings().collect()),
+            "matches" => Self::Matches(strings().filter_map(|v| Regex::new(&v).ok()).collect()),
+            "lessThan" => Self::LessThan(numbers()),
+            "lessThanOrEqual" => Self::LessThanOrEqual(numbers()),
+            "greaterThan" => Self::GreaterThan(numbers()),
+            "greaterThanOrEqual" => Self::GreaterThanOrEqual(numbers()),
+            "before" => Self::Before(times()),
+            "after" => Self::After(times()),
+            "semVerEqual" => Self::SemVerEqual(semvers()),
+            "semVerLessThan" => Self::SemVerLessThan(semvers()),
+            "semVerGreaterThan" => Self::SemVerGreaterThan(semvers()),
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Check a single user value against all clause values
+    pub(crate) fn matches(&self, user_value: &Value) -> bool {
+        match self {
+            Self::In {
+                strings,
+                numbers,
+                bools,
+            } => match user_value {
+                Value::String(s) => strings.contains(s),
+                Value::Number(n) => any(n.as_f64(), numbers, |a, b| a == *b),
+                Value::Bool(b) => bools.contains(b),
+                _ => false,
+            },
+            Self::StartsWith(values) => any(user_value.as_str(), values, |s, v| s.starts_with(v)),
+            Self::EndsWith(values) => any(user_value.as_str(), values, |s, v| s.ends_with(v)),
+            Self::Contains(values) => any(user_value.as_str(), values, |s, v| s.contains(v)),
+            Self::Matches(regexes) => any(user_value.as_str(), regexes, |s, re| re.is_match(s)),
+            Self::LessThan(values) => any(user_value.as_f64(), values, |a, b| a < *b),
+            Self::LessThanOrEqual(values) => any(user_value.as_f64(), values, |a, b| a <= *b),
+            Self::GreaterThan(values) => any(user_value.as_f64(), values, |a, b| a > *b),
+            Self::GreaterThanOrEqual(values) => any(user_value.as_f64(), values, |a, b| a >= *b),
+            Self::Before(values) => any(time(user_value), values, |a, b| a < *b),
+            Self::After(values) => any(time(user_value), values, |a, b| a > *b),
+            Self::SemVerEqual(values) => any(semver(user_value).as_ref(), values, |a, b| a == b),
+            Self::SemVerLessThan(values) => any(semver(user_value).as_ref(), values, |a, b| a < b),
+            Self::SemVerGreaterThan(values) => {
+                any(semver(user_value).as_ref(), values, |a, b| a > b)
+            }
+            Self::Unknown => false,
+        }
+    }
+}
+
+/// Whether the user value matches any of the clause values
+fn any<U: Copy, C, F: Fn(U, &C) -> bool>(user_value: Option<U>, values: &[C], f: F) -> bool {
+    match user_value {
+        Some(u) => values.iter().any(|v| f(u, v)),
+        None => false,
+    }
+}
+
+/// Timestamps are either unix milliseconds or RFC 3339 strings
+fn time(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => time_str(s),
+        _ => None,
+    }
+}
+
+fn time_str(value: &str) -> Option<f64> {
+    if let Ok(millis) = value.parse() {
+        return Some(millis);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|t| t.timestamp_millis() as f64)
+}
+
+fn semver(value: &Value) -> Option<Version> {
+    value.as_str().and_then(semver_str)
+}
+
+/// Parse a semantic version, allowing the minor and patch parts to be omitted
+///
+/// Build metadata is ignored for comparisons.
+fn semver_str(value: &str) -> Option<Version> {
+    let end = value.find(&['-', '+'][..]).unwrap_or(value.len());
+    let (core, rest) = value.split_at(end);
+    let padding = match core.matches('.').count() {
+        0 => ".0.0",
+        1 => ".0",
+        _ => "",
+    };
+    let mut version = Version::parse(&format!("{}{}{}", core, padding, rest)).ok()?;
+    version.build = BuildMetadata::EMPTY;
+    Some(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Operator;
+    use serde_json::{json, Value};
+
+    fn apply(op: &str, user_value: &Value, clause_value: Value) -> bool {
+        Operator::new(op, &[clause_value]).matches(user_value)
+    }
+
+    #[test]
+    fn strings() {
+        assert!(apply("in", &json!("abc"), json!("abc")));
+        assert!(!apply("in", &json!("abc"), json!("ab")));
+        assert!(apply("startsWith", &json!("abc"), json!("ab")));
+        assert!(apply("endsWith", &json!("abc"), json!("bc")));
+        assert!(apply("contains", &json!("abc"), json!("b")));
+        assert!(apply("matches", &json!("hello world"), json!("^hello\\s")));
+        assert!(!apply("matches", &json!("hello"), json!("(")));
+        assert!(!apply("startsWith", &json!(99), json!("9")));
+        assert!(!apply("startsWith", &json!("99"), json!(9)));
+    }
+
+    #[test]
+    fn numbers() {
+        assert!(apply("in", &json!(99), json!(99)));
+        assert!(apply("in", &json!(99.0), json!(99)));
+        assert!(!apply("in", &json!(99), json!("99")));
+        assert!(apply("lessThan", &json!(1), json!(1.5)));
+        assert!(!apply("lessThan", &json!(2), json!(1.5)));
+        assert!(apply("lessThanOrEqual", &json!(1), json!(1)));
+        assert!(apply("greaterThan", &json!(2), json!(1.5)));
+        assert!(apply("greaterThanOrEqual", &json!(1), json!(1)));
+        assert!(!apply("greaterThan", &json!("2"), json!(1)));
+        // numbers sent as strings are not numbers
+        assert!(!apply("greaterThan", &json!(2), json!("1")));
+    }
+
+    #[test]
+    fn dates() {
+        assert!(apply("before", &json!(0), json!(1000)));
+        assert!(apply("before", &json!("1970-01-01T00:00:00Z"), json!(1000)));
+        assert!(apply(
+            "after",
+            &json!(1_000_000),
+            json!("1970-01-01T00:00:01.000+00:00")
+        ));
+        assert!(!apply("after", &json!("not a date"), json!(0)));
+    }
+
+    #[test]
+    fn semantic_versions() {
+        assert!(apply("semVerEqual", &json!("2.0.0"), json!("2")));
+        assert!(apply("semVerEqual", &json!("2.1"), json!("2.1.0+build")));
+        assert!(apply(
+            "semVerLessThan",
+            &json!("2.0.0-rc.1"),
+            json!("2.0.0")
+        ));
+        assert!(apply("semVerGreaterThan", &json!("2.1.0"), json!("2.0.9")));
+        assert!(!apply("semVerEqual", &json!("x"), json!("2.0.0")));
+    }
+
+    #[test]
+    fn multiple_values() {
+        let op = Operator::new("in", &[json!("a"), json!(1), json!(true)]);
+        assert!(op.matches(&json!("a")));
+        assert!(op.matches(&json!(1)));
+        assert!(op.matches(&json!(true)));
+        assert!(!op.matches(&json!(false)));
+        assert!(!op.matches(&json!("1")));
+
+        // unparsable values are dropped
+        let op = Operator::new("matches", &[json!("("), json!("^b")]);
+        assert!(op.matches(&json!("bc")));
+        let op = Operator::new("lessThan", &[json!("x"), json!(10)]);
+        assert!(op.matches(&json!(5)));
+    }
+
+    #[test]
+    fn unknown_operator() {
+        for op in &["segmentMatch", "whatever"] {
+            let operator = Operator::new(op, &[json!("abc")]);
+            assert!(matches!(operator, Operator::Unknown));
+        }
+    }
+}
@@ -0,0 +1,739 @@
+//! Support for keeping flag data in an external database
+//!
+//! A [PersistentDataStore] only stores serialized items.
+//! Wrap it in a [CachingStore] to use it as a [Store].
+
+use crate::{
+    compiled::CompiledFlag,
+    consumer::{Consumer, InitState},
+    message::{InitData, Message, Update},
+    models::{FeatureFlagState, SegmentState},
+    store::{Store, StoreSnapshot},
+};
+use futures::future::{self, Ready};
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Kind of data held in a [PersistentDataStore]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataKind {
+    Features,
+    Segments,
+}
+
+impl DataKind {
+    /// Name used for the kind in databases
+    ///
+    /// Matches the names used by the other LaunchDarkly SDKs.
+    pub fn namespace(&self) -> &'static str {
+        match self {
+            Self::Features => "features",
+            Self::Segments => "segments",
+        }
+    }
+}
+
+/// Item of a [PersistentDataStore], serialized to json
+///
+/// Deleted items are kept as tombstones, so older versions
+/// can't overwrite a deletion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializedItem {
+    pub version: u64,
+    pub deleted: bool,
+    pub data: String,
+}
+
+impl SerializedItem {
+    /// Tombstone for an item deleted at a version
+    pub fn deleted(key: &str, version: u64) -> Self {
+        let data = serde_json::json!({ "key": key, "version": version, "deleted": true });
+        Self {
+            version,
+            deleted: true,
+            data: data.to_string(),
+        }
+    }
+}
+
+/// All items of a [PersistentDataStore] by kind and key
+pub type AllData = HashMap<DataKind, HashMap<String, SerializedItem>>;
+
+/// Database holding the flag data
+///
+/// Calls are blocking and should return quickly.
+pub trait PersistentDataStore {
+    type Error;
+
+    /// Replace all items of the kinds in `data` and mark the store as initialized
+    ///
+    /// Kinds missing from `data` are left untouched, they may be
+    /// written by other SDKs or the Relay Proxy sharing the database.
+    fn init(&self, data: AllData) -> Result<(), Self::Error>;
+
+    /// Get a single item, including tombstones
+    fn get(&self, kind: DataKind, key: &str) -> Result<Option<SerializedItem>, Self::Error>;
+
+    /// Get all items of a kind, including tombstones
+    fn get_all(&self, kind: DataKind) -> Result<HashMap<String, SerializedItem>, Self::Error>;
+
+    /// Insert or update an item
+    ///
+    /// Must not replace an item with the same or a newer version.
+    /// Returns whether the item was stored.
+    fn upsert(&self, kind: DataKind, key: &str, item: SerializedItem) -> Result<bool, Self::Error>;
+
+    /// Whether [init](Self::init) was called at some point,
+    /// possibly by another process
+    fn initialized(&self) -> Result<bool, Self::Error>;
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum Error<E>
+where
+    E: fmt::Debug + StdError + 'static,
+{
+    #[error("Failed to (de)serialize item: {0}")]
+    Serialization(Arc<serde_json::Error>),
+
+    #[error(transparent)]
+    Store(E),
+}
+
+impl<E> From<serde_json::Error> for Error<E>
+where
+    E: fmt::Debug + StdError + 'static,
+{
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serialization(Arc::new(e))
+    }
+}
+
+type Flags = HashMap<String, Arc<CompiledFlag>>;
+
+/// Value held in the cache until it expires
+struct Cached<T> {
+    value: T,
+    expires: Instant,
+}
+
+/// [Store] reading from a [PersistentDataStore], with an in-memory cache
+///
+/// Items are cached for the configured TTL, a TTL of zero disables caching.
+/// Implements [Consumer], so updates from a [Source](crate::source::Source)
+/// are written through to the persistent store.
+///
+/// [Snapshots](Store::snapshot) share the cache of all flags, without caching
+/// every snapshot reads all flags from the persistent store. Evaluating a single
+/// flag only reads the flag and its [prerequisites](Store::snapshot_for).
+pub struct CachingStore<P> {
+    store: P,
+    ttl: Duration,
+    flags: Mutex<HashMap<String, Cached<Option<Arc<CompiledFlag>>>>>,
+    all_flags: Mutex<Option<Cached<Arc<Flags>>>>,
+    init: AtomicBool,
+}
+
+impl<P> CachingStore<P>
+where
+    P: PersistentDataStore,
+    P::Error: fmt::Debug + StdError + 'static,
+{
+    /// Wrap a persistent store, caching items for `ttl`
+    pub fn new(store: P, ttl: Duration) -> Self {
+        Self {
+            store,
+            ttl,
+            flags: Mutex::new(HashMap::new()),
+            all_flags: Mutex::new(None),
+            init: AtomicBool::new(false),
+        }
+    }
+
+    /// The wrapped persistent store
+    pub fn inner(&self) -> &P {
+        &self.store
+    }
+
+    /// Remove all items from the cache
+    pub fn clear_cache(&self) {
+        self.flags.lock().unwrap().clear();
+        self.all_flags.lock().unwrap().take();
+    }
+
+    fn cache_flag(&self, key: String, flag: Option<Arc<CompiledFlag>>) {
+        if self.ttl.as_nanos() == 0 {
+            return;
+        }
+        let expires = Instant::now() + self.ttl;
+        self.flags.lock().unwrap().insert(
+            key,
+            Cached {
+                value: flag,
+                expires,
+            },
+        );
+    }
+
+    fn read_flag(&self, key: &str) -> Result<Option<Arc<CompiledFlag>>, Error<P::Error>> {
+        let item = self
+            .store
+            .get(DataKind::Features, key)
+            .map_err(Error::Store)?;
+        Ok(deserialize::<FeatureFlagState>(item)?.map(|f| Arc::new(f.into())))
+    }
+
+    fn read_all_flags(&self) -> Result<Flags, Error<P::Error>> {
+        let items = self
+            .store
+            .get_all(DataKind::Features)
+            .map_err(Error::Store)?;
+        let mut flags = HashMap::new();
+        for (key, item) in items {
+            if let Some(flag) = deserialize::<FeatureFlagState>(Some(item))? {
+                flags.insert(key, Arc::new(flag.into()));
+            }
+        }
+        Ok(flags)
+    }
+
+    fn read_all_segments(&self) -> Result<HashMap<String, Arc<SegmentState>>, Error<P::Error>> {
+        let items = self
+            .store
+            .get_all(DataKind::Segments)
+            .map_err(Error::Store)?;
+        let mut segments = HashMap::new();
+        for (key, item) in items {
+            if let Some(segment) = deserialize(Some(item))? {
+                segments.insert(key, Arc::new(segment));
+            }
+        }
+        Ok(segments)
+    }
+
+    /// All flags, from the cache if it hasn't expired
+    fn all_flags(&self) -> Arc<Flags> {
+        if let Some(cached) = self.all_flags.lock().unwrap().as_ref() {
+            if cached.expires > Instant::now() {
+                return Arc::clone(&cached.value);
+            }
+        }
+        match self.read_all_flags() {
+            Ok(flags) => {
+                let flags = Arc::new(flags);
+                if self.ttl.as_nanos() > 0 {
+                    self.all_flags.lock().unwrap().replace(Cached {
+                        value: Arc::clone(&flags),
+                        expires: Instant::now() + self.ttl,
+                    });
+                }
+                flags
+            }
+            Err(error) => {
+                warn!(%error, "failed to read flags from persistent store");
+                Arc::default()
+            }
+        }
+    }
+
+    /// Write all flags and segments, replacing the existing data
+    fn init(
+        &self,
+        flags: HashMap<String, FeatureFlagState>,
+        segments: HashMap<String, SegmentState>,
+    ) -> Result<(), Error<P::Error>> {
+        let mut items = HashMap::new();
+        for (key, flag) in &flags {
+            items.insert(key.clone(), serialize(flag, flag.version, flag.deleted)?);
+        }
+        let mut data = AllData::new();
+        data.insert(DataKind::Features, items);
+        let mut items = HashMap::new();
+        for (key, segment) in &segments {
+            items.insert(
+                key.clone(),
+                serialize(segment, segment.version, segment.deleted)?,
+            );
+        }
+        data.insert(DataKind::Segments, items);
+        self.store.init(data).map_err(Error::Store)?;
+
+        self.clear_cache();
+        if self.ttl.as_nanos() > 0 {
+            let flags: Flags = flags
+                .into_iter()
+                .map(|(k, f)| (k, Arc::new(f.into())))
+                .collect();
+            for (key, flag) in &flags {
+                self.cache_flag(key.clone(), Some(Arc::clone(flag)));
+            }
+            self.all_flags.lock().unwrap().replace(Cached {
+                value: Arc::new(flags),
+                expires: Instant::now() + self.ttl,
+            });
+        }
+        self.init.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Write a single item, or its tombstone
+    fn upsert_flag(
+        &self,
+        key: String,
+        item: SerializedItem,
+        flag: Option<Arc<CompiledFlag>>,
+    ) -> Result<(), Error<P::Error>> {
+        let updated = self
+            .store
+            .upsert(DataKind::Features, &key, item)
+            .map_err(Error::Store)?;
+        self.all_flags.lock().unwrap().take();
+        if updated {
+            self.cache_flag(key, flag);
+        } else {
+            // a newer version is stored, read it on the next access
+            self.flags.lock().unwrap().remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Write a single segment, or its tombstone
+    ///
+    /// Segments are not evaluated yet, so they are not cached.
+    fn upsert_segment(&self, key: String, item: SerializedItem) -> Result<(), Error<P::Error>> {
+        self.store
+            .upsert(DataKind::Segments, &key, item)
+            .map_err(Error::Store)?;
+        Ok(())
+    }
+}
+
+fn serialize<T, E>(item: &T, version: u64, deleted: bool) -> Result<SerializedItem, Error<E>>
+where
+    T: serde::Serialize,
+    E: fmt::Debug + StdError + 'static,
+{
+    Ok(SerializedItem {
+        version,
+        deleted,
+        data: serde_json::to_string(item)?,
+    })
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(
+    item: Option<SerializedItem>,
+) -> Result<Option<T>, serde_json::Error> {
+    match item {
+        Some(item) if !item.deleted => serde_json::from_str(&item.data).map(Some),
+        _ => Ok(None),
+    }
+}
+
+impl<P> Store for CachingStore<P>
+where
+    P: PersistentDataStore,
+    P::Error: fmt::Debug + StdError + 'static,
+{
+    fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>> {
+        if let Some(cached) = self.flags.lock().unwrap().get(name) {
+            if cached.expires > Instant::now() {
+                return cached.value.clone();
+            }
+        }
+        match self.read_flag(name) {
+            Ok(flag) => {
+                self.cache_flag(name.into(), flag.clone());
+                flag
+            }
+            Err(error) => {
+                warn!(%error, flag = name, "failed to read flag from persistent store");
+                None
+            }
+        }
+    }
+
+    fn export_all(&self) -> Flags {
+        let flags = self.all_flags();
+        Arc::try_unwrap(flags).unwrap_or_else(|flags| flags.as_ref().clone())
+    }
+
+    fn initialized(&self) -> bool {
+        // once initialized, the store stays initialized
+        if self.init.load(Ordering::SeqCst) {
+            return true;
+        }
+        match self.store.initialized() {
+            Ok(init) => {
+                self.init.store(init, Ordering::SeqCst);
+                init
+            }
+            Err(error) => {
+                warn!(%error, "failed to check if persistent store is initialized");
+                false
+            }
+        }
+    }
+
+    fn snapshot(&self) -> StoreSnapshot {
+        let initialized = self.initialized();
+        StoreSnapshot::new(self.all_flags(), initialized)
+    }
+
+    /// Reads all flags at once, so the flag and its prerequisites are
+    /// consistent, and keeps only the ones the evaluation needs
+    fn snapshot_for(&self, key: &str) -> StoreSnapshot {
+        let initialized = self.initialized();
+        let all = self.all_flags();
+        let mut flags = HashMap::new();
+        let mut pending = vec![key];
+        while let Some(key) = pending.pop() {
+            if flags.contains_key(key) {
+                continue;
+            }
+            if let Some(flag) = all.get(key) {
+                pending.extend(flag.prerequisites.iter().filter_map(|p| p.key.as_deref()));
+                flags.insert(key.to_string(), Arc::clone(flag));
+            }
+        }
+        StoreSnapshot::new(Arc::new(flags), initialized)
+    }
+
+    fn export_segments(&self) -> HashMap<String, Arc<SegmentState>> {
+        match self.read_all_segments() {
+            Ok(segments) => segments,
+            Err(error) => {
+                warn!(%error, "failed to read segments from persistent store");
+                HashMap::new()
+            }
+        }
+    }
+}
+
+impl<P, S> Consumer<S> for CachingStore<P>
+where
+    P: PersistentDataStore,
+    P::Error: fmt::Debug + StdError + Send + 'static,
+{
+    type Error = Error<P::Error>;
+    type Future = Ready<Result<InitState, Self::Error>>;
+
+    fn consume(&self, msg: Message) -> Self::Future {
+        let res = match msg {
+            // initialize flag data
+            Message::Put(InitData { flags, segments }) => self.init(flags, segments),
+            // update a single flag
+            Message::Patch(Update::Flag {
+                name,
+                data: Some(flag),
+                ..
+            }) => serialize(&flag, flag.version, flag.deleted)
+                .and_then(|item| self.upsert_flag(name, item, Some(Arc::new(flag.into())))),
+            // delete a flag
+            Message::Delete(Update::Flag {
+                name,
+                version: Some(version),
+                ..
+            }) => {
+                let item = SerializedItem::deleted(&name, version);
+                self.upsert_flag(name, item, None)
+            }
+            // update a single segment
+            Message::Patch(Update::Segment {
+                name,
+                data: Some(segment),
+                ..
+            }) => serialize(&segment, segment.version, segment.deleted)
+                .and_then(|item| self.upsert_segment(name, item)),
+            // delete a segment
+            Message::Delete(Update::Segment {
+                name,
+                version: Some(version),
+                ..
+            }) => {
+                let item = SerializedItem::deleted(&name, version);
+                self.upsert_segment(name, item)
+            }
+            msg => {
+                warn!(
+                    ?msg,
+                    "unknown update, missing some info or not yet implemented"
+                );
+                Ok(())
+            }
+        };
+        future::ready(res.map(|_| {
+            if self.init.load(Ordering::SeqCst) {
+                InitState::Done
+            } else {
+                InitState::Pending
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachingStore, DataKind, PersistentDataStore, SerializedItem};
+    use crate::{
+        consumer::{Consumer, InitState},
+        message::{InitData, Message, Update},
+        models::SegmentState,
+        store::Store,
+        test_utils::{FlagBuilder, InitSource, MockPersistentStore},
+    };
+    use futures::FutureExt;
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    type TestStore = CachingStore<MockPersistentStore>;
+
+    async fn consume(store: &TestStore, msg: Message) -> InitState {
+        <TestStore as Consumer<InitSource>>::consume(store, msg)
+            .await
+            .expect("failed to consume")
+    }
+
+    fn put(flags: Vec<crate::models::FeatureFlagState>) -> Message {
+        let flags = flags.into_iter().map(|f| (f.key.clone(), f)).collect();
+        Message::Put(InitData::with_flags(flags))
+    }
+
+    #[tokio::test]
+    async fn writes_through() {
+        let store = CachingStore::new(MockPersistentStore::default(), Duration::from_secs(60));
+        assert!(!store.initialized());
+
+        let mut flag = FlagBuilder::default().with_key("my_flag").into_inner();
+        flag.version = 1;
+        let state = consume(&store, put(vec![flag.clone()])).await;
+        assert!(matches!(state, InitState::Done));
+        assert!(store.initialized());
+        assert!(store.inner().initialized().unwrap());
+        assert_eq!(
+            1,
+            store
+                .inner()
+                .get(DataKind::Features, "my_flag")
+                .unwrap()
+                .unwrap()
+                .version
+        );
+
+        // outdated versions are ignored
+        flag.version = 0;
+        flag.on = false;
+        let patch = Message::Patch(Update::Flag {
+            name: "my_flag".into(),
+            data: Some(flag.clone()),
+            version: None,
+        });
+        consume(&store, patch).await;
+        assert!(store.flag("my_flag").unwrap().on);
+
+        let delete = Message::Delete(Update::Flag {
+            name: "my_flag".into(),
+            data: None,
+            version: Some(2),
+        });
+        consume(&store, delete).await;
+        assert!(store.flag("my_flag").is_none());
+        assert!(store.export_all().is_empty());
+        let tombstone = store
+            .inner()
+            .get(DataKind::Features, "my_flag")
+            .unwrap()
+            .unwrap();
+        assert!(tombstone.deleted);
+        assert_eq!(2, tombstone.version);
+    }
+
+    #[tokio::test]
+    async fn writes_segments() {
+        let store = CachingStore::new(MockPersistentStore::default(), Duration::from_secs(60));
+        let mut segment: SegmentState =
+            serde_json::from_str(include_str!("../tests/fixtures/segment.json")).unwrap();
+        let segments = vec![(segment.key.clone(), segment.clone())]
+            .into_iter()
+            .collect();
+        let init = InitData {
+            flags: HashMap::new(),
+            segments,
+        };
+        consume(&store, Message::Put(init)).await;
+        let item = store
+            .inner()
+            .get(DataKind::Segments, "beta-users")
+            .unwrap()
+            .unwrap();
+        assert_eq!(3, item.version);
+        assert_eq!(
+            segment,
+            serde_json::from_str::<SegmentState>(&item.data).unwrap()
+        );
+
+        segment.version = 4;
+        let patch = Message::Patch(Update::Segment {
+            name: "beta-users".into(),
+            data: Some(segment),
+            version: None,
+        });
+        consume(&store, patch).await;
+        let item = store.inner().get(DataKind::Segments, "beta-users").unwrap();
+        assert_eq!(4, item.unwrap().version);
+        assert_eq!(4, store.export_segments()["beta-users"].version);
+
+        let delete = Message::Delete(Update::Segment {
+            name: "beta-users".into(),
+            data: None,
+            version: Some(5),
+        });
+        consume(&store, delete).await;
+        let item = store.inner().get(DataKind::Segments, "beta-users").unwrap();
+        assert!(item.unwrap().deleted);
+        assert!(store.export_segments().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cache_ttl() {
+        let flag = FlagBuilder::default().with_key("my_flag").into_inner();
+        let cached = CachingStore::new(MockPersistentStore::default(), Duration::from_secs(60));
+        let uncached = CachingStore::new(MockPersistentStore::default(), Duration::from_secs(0));
+        for store in [&cached, &uncached].iter() {
+            consume(store, put(vec![flag.clone()])).await;
+            assert!(store.flag("my_flag").is_some());
+            // another process deletes the flag
+            store
+                .inner()
+                .upsert(
+                    DataKind::Features,
+                    "my_flag",
+                    SerializedItem::deleted("my_flag", 1),
+                )
+                .unwrap();
+        }
+
+        assert!(cached.flag("my_flag").is_some());
+        assert_eq!(1, cached.export_all().len());
+        cached.clear_cache();
+        assert!(cached.flag("my_flag").is_none());
+
+        assert!(uncached.flag("my_flag").is_none());
+        assert!(uncached.export_all().is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_for() {
+        let flags = vec![
+            FlagBuilder::default()
+                .with_key("dependent")
+                .add_prerequisite("prereq", 0)
+                .with_fallthrough_variation(1)
+                .into_inner(),
+            FlagBuilder::default()
+                .with_key("prereq")
+                .add_prerequisite("dependent", 1)
+                .into_inner(),
+            FlagBuilder::default().with_key("other").into_inner(),
+        ];
+        let store = CachingStore::new(MockPersistentStore::default(), Duration::from_secs(0));
+        consume(&store, put(flags)).await;
+
+        // only the flag and its prerequisites are read
+        let snapshot = store.snapshot_for("dependent");
+        let mut keys: Vec<_> = snapshot.flags().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(vec!["dependent", "prereq"], keys);
+        assert!(snapshot.initialized());
+        assert!(store.snapshot_for("missing").flags().is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_for_concurrent_update() {
+        let flag = |key: &str, version| {
+            let builder = FlagBuilder::default().with_key(key);
+            let builder = match key {
+                "dependent" => builder.add_prerequisite("prereq", 0),
+                _ => builder,
+            };
+            let mut flag = builder.into_inner();
+            flag.version = version;
+            flag
+        };
+        let store = Arc::new(CachingStore::new(
+            MockPersistentStore::default(),
+            Duration::from_secs(0),
+        ));
+        consume(&store, put(vec![flag("dependent", 1), flag("prereq", 1)])).await;
+
+        // the prerequisite is always updated first
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let store = Arc::clone(&store);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                for version in 2..500 {
+                    for key in ["prereq", "dependent"].iter() {
+                        let patch = Message::Patch(Update::Flag {
+                            name: key.to_string(),
+                            data: Some(flag(key, version)),
+                            version: None,
+                        });
+                        consume(&store, patch).now_or_never().unwrap();
+                    }
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        loop {
+            let finished = done.load(Ordering::SeqCst);
+            let snapshot = store.snapshot_for("dependent");
+            let dependent = snapshot.flags()["dependent"].version;
+            let prereq = snapshot.flags()["prereq"].version;
+            assert!(
+                prereq == dependent || prereq == dependent + 1,
+                "prereq version {} read with dependent version {}",
+                prereq,
+                dependent
+            );
+            if finished {
+                break;
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_from() {
+        let flag = FlagBuilder::default().with_key("my_flag").into_inner();
+        let source = InitSource(vec![flag]);
+        let store = Arc::new(CachingStore::new(
+            MockPersistentStore::default(),
+            Duration::from_secs(60),
+        ));
+        let init = Arc::clone(&store).read_from(source);
+        init.await.expect("failed to init");
+        assert!(store.inner().initialized().unwrap());
+        assert!(store.flag("my_flag").is_some());
+    }
+}
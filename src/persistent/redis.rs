@@ -0,0 +1,304 @@
+//! [PersistentDataStore] sharing the flag data through Redis
+//!
+//! Uses the same layout as the other LaunchDarkly SDKs and the Relay Proxy:
+//! a hash per data kind at `<prefix>:features` and `<prefix>:segments`,
+//! plus a `<prefix>:$inited` marker.
+//!
+//! [RedisStore] only reads and writes serialized items. To evaluate flags
+//! from it or write stream updates to it, wrap it in a [CachingStore],
+//! e.g. using [RedisStore::caching].
+//!
+//! Requires the `redis` feature.
+
+use super::{AllData, CachingStore, DataKind, PersistentDataStore, SerializedItem};
+use redis::{Commands, Connection, IntoConnectionInfo};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::debug;
+
+/// Prefix used by default by all LaunchDarkly SDKs
+pub const DEFAULT_PREFIX: &str = "launchdarkly";
+
+/// Attempts of an upsert before giving up on concurrent modifications
+const MAX_UPSERT_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum Error {
+    #[error("Redis error: {0}")]
+    Redis(Arc<redis::RedisError>),
+
+    #[error("Invalid item in redis: {0}")]
+    InvalidItem(Arc<serde_json::Error>),
+
+    #[error("Gave up updating {0} after too many concurrent modifications")]
+    Contention(String),
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(e: redis::RedisError) -> Self {
+        Self::Redis(Arc::new(e))
+    }
+}
+
+/// Metadata contained in every stored item
+#[derive(Deserialize)]
+struct ItemMeta {
+    version: u64,
+    #[serde(default)]
+    deleted: bool,
+}
+
+fn parse_item(data: String) -> Result<SerializedItem, Error> {
+    let meta: ItemMeta =
+        serde_json::from_str(&data).map_err(|e| Error::InvalidItem(Arc::new(e)))?;
+    Ok(SerializedItem {
+        version: meta.version,
+        deleted: meta.deleted,
+        data,
+    })
+}
+
+/// Stores flags and segments in Redis hashes
+///
+/// Can be shared with other SDKs and a Relay Proxy using the same prefix.
+/// This is not a [Store](crate::store::Store) by itself,
+/// use [caching](Self::caching) to get one.
+pub struct RedisStore {
+    conn: Mutex<Connection>,
+    prefix: String,
+}
+
+impl RedisStore {
+    /// Connect to Redis, e.g. using an url like `redis://localhost:6379`
+    pub fn open<T: IntoConnectionInfo>(info: T, prefix: &str) -> Result<Self, Error> {
+        let conn = redis::Client::open(info)?.get_connection()?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            prefix: prefix.into(),
+        })
+    }
+
+    /// Wrap the store in a [CachingStore], caching items for `ttl`
+    ///
+    /// The result is a [Store](crate::store::Store) for evaluations and a
+    /// [Consumer](crate::consumer::Consumer) writing updates through to Redis.
+    /// A `ttl` of zero reads from Redis on every access.
+    pub fn caching(self, ttl: Duration) -> CachingStore<Self> {
+        CachingStore::new(self, ttl)
+    }
+
+    fn key(&self, kind: DataKind) -> String {
+        format!("{}:{}", self.prefix, kind.namespace())
+    }
+
+    fn inited_key(&self) -> String {
+        format!("{}:$inited", self.prefix)
+    }
+}
+
+impl PersistentDataStore for RedisStore {
+    type Error = Error;
+
+    fn init(&self, data: AllData) -> Result<(), Self::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (kind, items) in &data {
+            pipe.del(self.key(*kind)).ignore();
+            let items: Vec<_> = items.iter().map(|(k, i)| (k, &i.data)).collect();
+            if !items.is_empty() {
+                pipe.hset_multiple(self.key(*kind), &items).ignore();
+            }
+        }
+        pipe.set(self.inited_key(), "").ignore();
+        pipe.query::<()>(&mut *conn)?;
+        Ok(())
+    }
+
+    fn get(&self, kind: DataKind, key: &str) -> Result<Option<SerializedItem>, Self::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let data: Option<String> = conn.hget(self.key(kind), key)?;
+        data.map(parse_item).transpose()
+    }
+
+    fn get_all(&self, kind: DataKind) -> Result<HashMap<String, SerializedItem>, Self::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let items: HashMap<String, String> = conn.hgetall(self.key(kind))?;
+        items
+            .into_iter()
+            .map(|(key, data)| Ok((key, parse_item(data)?)))
+            .collect()
+    }
+
+    fn upsert(&self, kind: DataKind, key: &str, item: SerializedItem) -> Result<bool, Self::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let hash = self.key(kind);
+        for _ in 0..MAX_UPSERT_ATTEMPTS {
+            // the transaction fails if the hash changes after this
+            redis::cmd("WATCH").arg(&hash).query::<()>(&mut *conn)?;
+            let existing: Option<String> = conn.hget(&hash, key)?;
+            if let Some(existing) = existing.map(parse_item).transpose()? {
+                if existing.version >= item.version {
+                    redis::cmd("UNWATCH").query::<()>(&mut *conn)?;
+                    return Ok(false);
+                }
+            }
+            let res: Option<()> = redis::pipe()
+                .atomic()
+                .hset(&hash, key, &item.data)
+                .ignore()
+                .query(&mut *conn)?;
+            if res.is_some() {
+                return Ok(true);
+            }
+            debug!(key, "concurrent modification in redis, retrying upsert");
+        }
+        Err(Error::Contention(key.into()))
+    }
+
+    fn initialized(&self) -> Result<bool, Self::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        Ok(conn.exists(self.inited_key())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RedisStore, MAX_UPSERT_ATTEMPTS};
+    use crate::{
+        consumer::Consumer,
+        message::{InitData, Message},
+        persistent::{AllData, DataKind, PersistentDataStore, SerializedItem},
+        store::Store,
+        test_utils::{FlagBuilder, NullSource},
+    };
+    use redis::Commands;
+    use std::{env, process, thread, time::Duration};
+
+    /// Connect to the server in `LD_TEST_REDIS_URL`, using a prefix unique to the test
+    ///
+    /// Returns `None` to skip the test if the variable is not set.
+    /// Keys under the prefix are removed first.
+    fn connect(name: &str) -> Option<(RedisStore, redis::Connection, String)> {
+        let url = env::var("LD_TEST_REDIS_URL").ok()?;
+        let prefix = format!("ld-test-{}-{}", process::id(), name);
+        let mut conn = redis::Client::open(url.as_str())
+            .and_then(|client| client.get_connection())
+            .expect("failed to connect");
+        let keys = ["features", "segments", "$inited"].iter();
+        let _: () = conn
+            .del(
+                keys.map(|key| format!("{}:{}", prefix, key))
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+        let store = RedisStore::open(url.as_str(), &prefix).expect("failed to connect");
+        Some((store, conn, prefix))
+    }
+
+    fn item(version: u64) -> SerializedItem {
+        SerializedItem {
+            version,
+            deleted: false,
+            data: format!("{{\"key\":\"a\",\"version\":{}}}", version),
+        }
+    }
+
+    #[test]
+    fn key_layout() {
+        let (store, mut conn, prefix) = match connect("layout") {
+            Some(redis) => redis,
+            None => return,
+        };
+        assert!(!store.initialized().unwrap());
+
+        let mut data = AllData::new();
+        let features = vec![("a".to_string(), item(1))].into_iter().collect();
+        data.insert(DataKind::Features, features);
+        store.upsert(DataKind::Segments, "s", item(1)).unwrap();
+        store.init(data).unwrap();
+
+        assert!(store.initialized().unwrap());
+        let inited: bool = conn.exists(format!("{}:$inited", prefix)).unwrap();
+        assert!(inited);
+        let stored: Option<String> = conn.hget(format!("{}:features", prefix), "a").unwrap();
+        assert_eq!(Some(item(1).data), stored);
+        assert_eq!(Some(item(1)), store.get(DataKind::Features, "a").unwrap());
+        assert_eq!(1, store.get_all(DataKind::Features).unwrap().len());
+        // kinds missing from the data are kept
+        assert_eq!(Some(item(1)), store.get(DataKind::Segments, "s").unwrap());
+    }
+
+    #[test]
+    fn versioned_upsert() {
+        let (store, _, _) = match connect("upsert") {
+            Some(redis) => redis,
+            None => return,
+        };
+        assert!(store.upsert(DataKind::Features, "a", item(2)).unwrap());
+        assert!(!store.upsert(DataKind::Features, "a", item(2)).unwrap());
+        assert!(!store.upsert(DataKind::Features, "a", item(1)).unwrap());
+
+        let tombstone = SerializedItem::deleted("a", 3);
+        assert!(store
+            .upsert(DataKind::Features, "a", tombstone.clone())
+            .unwrap());
+        assert_eq!(Some(tombstone), store.get(DataKind::Features, "a").unwrap());
+    }
+
+    #[test]
+    fn concurrent_upserts() {
+        let (store, _, prefix) = match connect("concurrent") {
+            Some(redis) => redis,
+            None => return,
+        };
+        let url = env::var("LD_TEST_REDIS_URL").unwrap();
+        // two writers on separate connections race on the same hash,
+        // aborted transactions are retried. Every abort is caused by a write
+        // of the other one, so fewer writes than attempts always succeed.
+        let writes = MAX_UPSERT_ATTEMPTS as u64 - 1;
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let store = RedisStore::open(url.as_str(), &prefix).expect("failed to connect");
+                thread::spawn(move || {
+                    for version in 1..=writes {
+                        store
+                            .upsert(DataKind::Features, &writer.to_string(), item(version))
+                            .expect("upsert failed");
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let all = store.get_all(DataKind::Features).unwrap();
+        assert_eq!(2, all.len());
+        assert!(all.values().all(|item| item.version == writes));
+    }
+
+    #[tokio::test]
+    async fn caching_store() {
+        let (redis, _, _) = match connect("caching") {
+            Some(redis) => redis,
+            None => return,
+        };
+        let store = redis.caching(Duration::from_secs(0));
+        let flag = FlagBuilder::default().with_key("my_flag").into_inner();
+        let flags = vec![(flag.key.clone(), flag)].into_iter().collect();
+        <_ as Consumer<NullSource>>::consume(&store, Message::Put(InitData::with_flags(flags)))
+            .await
+            .expect("failed to consume");
+        assert!(store.initialized());
+        assert!(store.flag("my_flag").is_some());
+        assert!(store
+            .inner()
+            .get(DataKind::Features, "my_flag")
+            .unwrap()
+            .is_some());
+    }
+}
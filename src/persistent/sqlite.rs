@@ -0,0 +1,258 @@
+//! [PersistentDataStore] keeping the flag data in a SQLite database
+//!
+//! Requires the `sqlite` feature.
+
+use super::{AllData, DataKind, PersistentDataStore, SerializedItem};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS items (
+        kind TEXT NOT NULL,
+        key TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        deleted INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (kind, key)
+    );
+    CREATE TABLE IF NOT EXISTS inited (
+        id INTEGER PRIMARY KEY CHECK (id = 0)
+    );
+";
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum Error {
+    #[error("SQLite error: {0}")]
+    Sqlite(Arc<rusqlite::Error>),
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(Arc::new(e))
+    }
+}
+
+/// Stores flags and segments in a single table of a SQLite database
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open or create a database file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Create a database living only as long as the store
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    /// Use an existing connection, creating the tables if needed
+    pub fn with_connection(conn: Connection) -> Result<Self, Error> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+fn read_item(row: &rusqlite::Row) -> rusqlite::Result<SerializedItem> {
+    Ok(SerializedItem {
+        version: row.get::<_, i64>("version")? as u64,
+        deleted: row.get("deleted")?,
+        data: row.get("data")?,
+    })
+}
+
+impl PersistentDataStore for SqliteStore {
+    type Error = Error;
+
+    fn init(&self, data: AllData) -> Result<(), Self::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        // replace everything at once, readers never see partial data
+        let tx = conn.transaction()?;
+        for kind in data.keys() {
+            tx.execute(
+                "DELETE FROM items WHERE kind = ?",
+                params![kind.namespace()],
+            )?;
+        }
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO items (kind, key, version, deleted, data) VALUES (?, ?, ?, ?, ?)",
+            )?;
+            for (kind, items) in &data {
+                for (key, item) in items {
+                    insert.execute(params![
+                        kind.namespace(),
+                        key,
+                        item.version as i64,
+                        item.deleted,
+                        item.data
+                    ])?;
+                }
+            }
+        }
+        tx.execute("INSERT OR IGNORE INTO inited (id) VALUES (0)", params![])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get(&self, kind: DataKind, key: &str) -> Result<Option<SerializedItem>, Self::Error> {
+        let conn = self.conn.lock().unwrap();
+        let item = conn
+            .query_row(
+                "SELECT version, deleted, data FROM items WHERE kind = ? AND key = ?",
+                params![kind.namespace(), key],
+                read_item,
+            )
+            .optional()?;
+        Ok(item)
+    }
+
+    fn get_all(&self, kind: DataKind) -> Result<HashMap<String, SerializedItem>, Self::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut query =
+            conn.prepare("SELECT key, version, deleted, data FROM items WHERE kind = ?")?;
+        let rows = query.query_map(params![kind.namespace()], |row| {
+            Ok((row.get("key")?, read_item(row)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn upsert(&self, kind: DataKind, key: &str, item: SerializedItem) -> Result<bool, Self::Error> {
+        let conn = self.conn.lock().unwrap();
+        // only replaces existing rows with an older version
+        let changed = conn.execute(
+            "INSERT INTO items (kind, key, version, deleted, data) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT (kind, key) DO UPDATE SET
+                version = excluded.version,
+                deleted = excluded.deleted,
+                data = excluded.data
+             WHERE excluded.version > items.version",
+            params![
+                kind.namespace(),
+                key,
+                item.version as i64,
+                item.deleted,
+                item.data
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    fn initialized(&self) -> Result<bool, Self::Error> {
+        let conn = self.conn.lock().unwrap();
+        let inited = conn
+            .query_row("SELECT 1 FROM inited", params![], |_| Ok(()))
+            .optional()?;
+        Ok(inited.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteStore;
+    use crate::{
+        consumer::Consumer,
+        message::{InitData, Message},
+        persistent::{AllData, CachingStore, DataKind, PersistentDataStore, SerializedItem},
+        store::Store,
+        test_utils::{FlagBuilder, NullSource},
+    };
+    use std::{env, fs, process, time::Duration};
+
+    fn item(version: u64) -> SerializedItem {
+        SerializedItem {
+            version,
+            deleted: false,
+            data: format!("{{\"version\":{}}}", version),
+        }
+    }
+
+    fn data(items: Vec<(&str, SerializedItem)>) -> AllData {
+        let mut data = AllData::new();
+        let features = items.into_iter().map(|(k, i)| (k.to_string(), i)).collect();
+        data.insert(DataKind::Features, features);
+        data
+    }
+
+    #[test]
+    fn init_replaces_data() {
+        let store = SqliteStore::open_in_memory().expect("failed to open");
+        assert!(!store.initialized().unwrap());
+        store.upsert(DataKind::Segments, "s", item(1)).unwrap();
+
+        store
+            .init(data(vec![("a", item(1)), ("b", item(1))]))
+            .unwrap();
+        assert!(store.initialized().unwrap());
+        assert_eq!(2, store.get_all(DataKind::Features).unwrap().len());
+        // kinds missing from the data are kept
+        assert_eq!(Some(item(1)), store.get(DataKind::Segments, "s").unwrap());
+
+        store.init(data(vec![("c", item(1))])).unwrap();
+        let all = store.get_all(DataKind::Features).unwrap();
+        assert_eq!(vec!["c"], all.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn versioned_upsert() {
+        let store = SqliteStore::open_in_memory().expect("failed to open");
+        assert!(store.upsert(DataKind::Features, "a", item(2)).unwrap());
+        assert!(!store.upsert(DataKind::Features, "a", item(2)).unwrap());
+        assert!(!store.upsert(DataKind::Features, "a", item(1)).unwrap());
+        assert_eq!(Some(item(2)), store.get(DataKind::Features, "a").unwrap());
+
+        let tombstone = SerializedItem::deleted("a", 3);
+        assert!(store
+            .upsert(DataKind::Features, "a", tombstone.clone())
+            .unwrap());
+        assert_eq!(Some(tombstone), store.get(DataKind::Features, "a").unwrap());
+        // kinds are separate
+        assert_eq!(None, store.get(DataKind::Segments, "a").unwrap());
+    }
+
+    #[test]
+    fn reopen_file() {
+        let path = env::temp_dir().join(format!("ld-sqlite-test-{}.db", process::id()));
+        {
+            let store = SqliteStore::open(&path).expect("failed to open");
+            store.init(data(vec![("a", item(1))])).unwrap();
+        }
+        let store = SqliteStore::open(&path).expect("failed to reopen");
+        assert!(store.initialized().unwrap());
+        assert_eq!(Some(item(1)), store.get(DataKind::Features, "a").unwrap());
+        drop(store);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn warm_start() {
+        let path = env::temp_dir().join(format!("ld-sqlite-warm-{}.db", process::id()));
+        {
+            let sqlite = SqliteStore::open(&path).expect("failed to open");
+            let store = CachingStore::new(sqlite, Duration::from_secs(60));
+            let flag = FlagBuilder::default().with_key("my_flag").into_inner();
+            let flags = vec![(flag.key.clone(), flag)].into_iter().collect();
+            <CachingStore<_> as Consumer<NullSource>>::consume(
+                &store,
+                Message::Put(InitData::with_flags(flags)),
+            )
+            .await
+            .expect("failed to consume");
+        }
+        // a restarted process can use the data without receiving it again
+        let sqlite = SqliteStore::open(&path).expect("failed to reopen");
+        let store = CachingStore::new(sqlite, Duration::from_secs(60));
+        assert!(store.initialized());
+        assert!(store.flag("my_flag").is_some());
+        drop(store);
+        let _ = fs::remove_file(&path);
+    }
+}
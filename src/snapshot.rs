@@ -0,0 +1,154 @@
+//! Snapshots of the flag data for warm starts
+//!
+//! A service writes a snapshot of its last known flags and imports it
+//! on the next boot, so it can evaluate before the stream connects.
+
+use crate::{
+    events::now_millis,
+    message::{InitData, Message},
+    models::{FeatureFlagState, SegmentState},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+/// Format version written by this SDK
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to access snapshot file: {0}")]
+    Io(Arc<io::Error>),
+
+    #[error("Invalid snapshot: {0}")]
+    Invalid(Arc<serde_json::Error>),
+
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(Arc::new(e))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Invalid(Arc::new(e))
+    }
+}
+
+/// All flags and segments at a point in time
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub version: u32,
+    /// Creation time in milliseconds since the unix epoch
+    pub created_at: u64,
+    pub flags: HashMap<String, FeatureFlagState>,
+    #[serde(default)]
+    pub segments: HashMap<String, SegmentState>,
+}
+
+impl Snapshot {
+    /// Create a snapshot of the current time
+    pub fn new(
+        flags: HashMap<String, FeatureFlagState>,
+        segments: HashMap<String, SegmentState>,
+    ) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            created_at: now_millis(),
+            flags,
+            segments,
+        }
+    }
+
+    /// Parse a snapshot, rejecting unknown versions
+    pub fn read_from<R: Read>(reader: R) -> Result<Self, Error> {
+        let snapshot: Self = serde_json::from_reader(reader)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(Error::UnsupportedVersion(snapshot.version));
+        }
+        Ok(snapshot)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<(), Error> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Read a snapshot file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Write a snapshot file
+    ///
+    /// The file is replaced at once, readers never see a partial snapshot.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            self.write_to(&mut writer)?;
+            writer.flush()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl From<Snapshot> for Message {
+    fn from(snapshot: Snapshot) -> Self {
+        Message::Put(InitData {
+            flags: snapshot.flags,
+            segments: snapshot.segments,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Snapshot};
+    use crate::{message::Message, models::SegmentState, test_utils::FlagBuilder};
+    use std::{env, fs, process};
+
+    #[test]
+    fn save_and_load() {
+        let flag = FlagBuilder::default().with_key("my_flag").into_inner();
+        let segment: SegmentState =
+            serde_json::from_str(include_str!("../tests/fixtures/segment.json")).unwrap();
+        let snapshot = Snapshot::new(
+            vec![(flag.key.clone(), flag)].into_iter().collect(),
+            vec![(segment.key.clone(), segment)].into_iter().collect(),
+        );
+
+        let path = env::temp_dir().join(format!("ld-snapshot-{}.json", process::id()));
+        snapshot.save(&path).expect("failed to save");
+        let loaded = Snapshot::load(&path).expect("failed to load");
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(snapshot.created_at, loaded.created_at);
+        assert_eq!(vec!["my_flag"], loaded.flags.keys().collect::<Vec<_>>());
+        assert_eq!(snapshot.segments, loaded.segments);
+
+        match Message::from(loaded) {
+            Message::Put(data) => assert_eq!(snapshot.segments, data.segments),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let json = r#"{"version":2,"createdAt":0,"flags":{}}"#;
+        let result = Snapshot::read_from(json.as_bytes());
+        assert!(matches!(result, Err(Error::UnsupportedVersion(2))));
+    }
+}
@@ -1,10 +1,11 @@
 use crate::message::{Message, MessageParseError};
 use eventsource_client::{Client, Event, EventStream, HttpsConnector};
 use futures::{ready, Stream};
+use http::StatusCode;
 use pin_project::pin_project;
 use std::sync::Arc;
 use std::{
-    convert::TryInto,
+    convert::{Infallible, TryInto},
     fmt::{Debug, Display},
     pin::Pin,
     task::{Context, Poll},
@@ -24,6 +25,12 @@ pub trait Source {
     /// this should be called again to get a
     /// fresh stream.
     fn stream(&self) -> Self::Stream;
+
+    /// Whether reconnecting after this error is pointless,
+    /// e.g. because the SDK key was rejected
+    fn is_unrecoverable(&self, _error: &Self::Error) -> bool {
+        false
+    }
 }
 
 impl<T: Source> Source for Arc<T> {
@@ -32,6 +39,25 @@ impl<T: Source> Source for Arc<T> {
     fn stream(&self) -> Self::Stream {
         self.as_ref().stream()
     }
+
+    fn is_unrecoverable(&self, error: &Self::Error) -> bool {
+        self.as_ref().is_unrecoverable(error)
+    }
+}
+
+/// Placeholder for clients without a [Source]
+///
+/// Used in daemon mode, where the flag data is written to
+/// a shared store by another process.
+pub enum NoSource {}
+
+impl Source for NoSource {
+    type Error = Infallible;
+    type Stream = futures::stream::Pending<Result<Message, Self::Error>>;
+
+    fn stream(&self) -> Self::Stream {
+        match *self {}
+    }
 }
 
 /// [Source] for reading from an SSE stream.
@@ -44,12 +70,19 @@ pub struct SseSource {
 impl SseSource {
     /// Create a [Source] consuming from SSE with an SDK token
     pub fn new<T: AsRef<str>>(token: T) -> Self {
-        let client = eventsource_client::Client::for_url(DEFAULT_BASE_URL)
-            .unwrap()
-            .header("Authorization", token.as_ref())
-            .unwrap()
+        Self::with_url(DEFAULT_BASE_URL, token).unwrap()
+    }
+
+    /// Create a [Source] consuming from SSE at another URL,
+    /// like a relay proxy or a mock server in tests
+    pub fn with_url<U: AsRef<str>, T: AsRef<str>>(
+        url: U,
+        token: T,
+    ) -> Result<Self, eventsource_client::Error> {
+        let client = Client::for_url(url.as_ref())?
+            .header("Authorization", token.as_ref())?
             .build();
-        Self { client }
+        Ok(Self { client })
     }
 }
 
@@ -60,6 +93,14 @@ impl Source for SseSource {
     fn stream(&self) -> Self::Stream {
         MessageStream(Box::pin(self.client.stream()))
     }
+
+    fn is_unrecoverable(&self, error: &Self::Error) -> bool {
+        matches!(
+            error,
+            StreamError::Inner(eventsource_client::Error::HttpRequest(status))
+                if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN
+        )
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -100,3 +141,165 @@ where
         Poll::Ready(Some(Ok(message)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageStream, SseSource, StreamError};
+    use crate::{
+        consumer::{Consumer, ReadError},
+        message::{Message, MessageParseError, Update},
+        store::{MemoryStore, Store},
+        test_utils::FlagBuilder,
+        testing::MockStreamServer,
+    };
+    use eventsource_client::Event;
+    use futures::{stream, StreamExt};
+    use serde_json::json;
+    use std::{sync::Arc, time::Duration};
+    use tokio::time;
+
+    fn event(name: &str, data: Option<serde_json::Value>) -> Event {
+        let mut event = Event::new();
+        event.event_type = name.into();
+        if let Some(data) = data {
+            event.set_field("data", data.to_string().as_bytes());
+        }
+        event
+    }
+
+    /// Wait for a condition to be met by the background task
+    async fn eventually<F: Fn() -> bool>(check: F) {
+        for _ in 0..500 {
+            if check() {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    fn start(server: &MockStreamServer) -> (Arc<MemoryStore>, crate::consumer::ReadHandle) {
+        let store = Arc::new(MemoryStore::new());
+        let source = SseSource::with_url(server.url(), "sdk-key").unwrap();
+        let (handle, _) = Arc::clone(&store).read_from_with_handle(source);
+        (store, handle)
+    }
+
+    #[tokio::test]
+    async fn message_stream() {
+        let events = vec![
+            Ok(event(
+                "put",
+                Some(json!({ "path": "/", "data": { "flags": {} } })),
+            )),
+            Err("connection lost"),
+            Ok(event("patch", None)),
+        ];
+        let mut messages = MessageStream(stream::iter(events));
+
+        assert!(matches!(messages.next().await, Some(Ok(Message::Put(_)))));
+        assert!(matches!(
+            messages.next().await,
+            Some(Err(StreamError::Inner("connection lost")))
+        ));
+        assert!(matches!(
+            messages.next().await,
+            Some(Err(StreamError::Parse(
+                MessageParseError::MissingEventPayload
+            )))
+        ));
+        assert!(messages.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_updates() {
+        let server = MockStreamServer::start();
+        server.set_flags(vec![FlagBuilder::default().with_key("a").into_inner()]);
+        let (store, _handle) = start(&server);
+        eventually(|| store.initialized()).await;
+        assert!(store.flag("a").is_some());
+        assert_eq!(vec![Some("sdk-key".to_string())], server.requests());
+
+        server.heartbeat();
+        let flag = FlagBuilder::default().with_key("b").into_inner();
+        server.send(&Message::Patch(Update::Flag {
+            name: "b".into(),
+            data: Some(flag),
+            version: None,
+        }));
+        eventually(|| store.flag("b").is_some()).await;
+
+        server.send(&Message::Delete(Update::Flag {
+            name: "a".into(),
+            data: None,
+            version: Some(1),
+        }));
+        eventually(|| store.flag("a").is_none()).await;
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_disconnect() {
+        let server = MockStreamServer::start();
+        server.set_flags(vec![FlagBuilder::default().with_key("a").into_inner()]);
+        let (store, _handle) = start(&server);
+        eventually(|| store.initialized()).await;
+
+        server.set_flags(vec![FlagBuilder::default().with_key("b").into_inner()]);
+        server.disconnect();
+        eventually(|| store.flag("b").is_some()).await;
+        assert!(store.flag("a").is_none());
+        assert_eq!(2, server.requests().len());
+    }
+
+    #[tokio::test]
+    async fn retries_http_errors() {
+        let server = MockStreamServer::start();
+        server.fail_next(500);
+        server.fail_next(503);
+        let store = Arc::new(MemoryStore::new());
+        let source = SseSource::with_url(server.url(), "sdk-key").unwrap();
+        let (_handle, init) = Arc::clone(&store).read_from_with_handle(source);
+
+        time::timeout(Duration::from_secs(5), init)
+            .await
+            .expect("init timed out")
+            .expect("init failed");
+        assert_eq!(3, server.requests().len());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_failures() {
+        let server = MockStreamServer::start();
+        for _ in 0..4 {
+            server.fail_next(500);
+        }
+        let store = Arc::new(MemoryStore::new());
+        let source = SseSource::with_url(server.url(), "sdk-key").unwrap();
+        let (_handle, init) = Arc::clone(&store).read_from_with_handle(source);
+
+        let result = time::timeout(Duration::from_secs(5), init)
+            .await
+            .expect("init timed out");
+        assert!(matches!(result, Err(ReadError::RetryFailed)));
+        assert!(!store.initialized());
+        assert_eq!(4, server.requests().len());
+    }
+
+    #[tokio::test]
+    async fn rejects_unauthorized() {
+        let server = MockStreamServer::start();
+        server.fail_next(401);
+        let store = Arc::new(MemoryStore::new());
+        let source = SseSource::with_url(server.url(), "sdk-key").unwrap();
+        let (_handle, init) = Arc::clone(&store).read_from_with_handle(source);
+
+        let result = time::timeout(Duration::from_secs(5), init)
+            .await
+            .expect("init timed out");
+        // a rejected SDK key is not retried
+        assert!(matches!(result, Err(ReadError::Rejected(_))));
+        assert!(!store.initialized());
+        assert_eq!(1, server.requests().len());
+    }
+}
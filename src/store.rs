@@ -1,7 +1,9 @@
 use crate::{
+    compiled::CompiledFlag,
     consumer::{Consumer, InitState},
     message::{InitData, Message, Update},
-    models::FeatureFlagState,
+    models::SegmentState,
+    validation::{update_diagnostics, validate_flags, Diagnostic},
 };
 use arc_swap::ArcSwap;
 use futures::future::{self, Ready};
@@ -15,13 +17,102 @@ use std::{
 };
 use tracing::{info, warn};
 
+/// Flag data stored in the SDK
+///
+/// Flags are [compiled](CompiledFlag) when they enter the store and
+/// handed out as `Arc`s, so evaluations don't need to copy the flag data.
 pub trait Store {
-    fn flag(&self, name: &str) -> Option<FeatureFlagState>;
-    fn export_all(&self) -> HashMap<String, FeatureFlagState>;
+    fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>>;
+    fn export_all(&self) -> HashMap<String, Arc<CompiledFlag>>;
+
+    /// Whether the store has received its initial data
+    fn initialized(&self) -> bool;
+
+    /// Problems found in the stored flags, by flag key
+    ///
+    /// Only contains flags with problems.
+    fn diagnostics(&self) -> HashMap<String, Vec<Diagnostic>> {
+        validate_flags(&self.export_all())
+    }
+
+    /// All flags at one point in time
+    ///
+    /// Flags with prerequisites and batches are evaluated from a snapshot, so a flag
+    /// and its prerequisites come from the same version of the data even while
+    /// updates arrive.
+    /// The default implementation copies the flags with [export_all](Self::export_all),
+    /// stores that can do better should override it.
+    fn snapshot(&self) -> StoreSnapshot {
+        let initialized = self.initialized();
+        StoreSnapshot::new(Arc::new(self.export_all()), initialized)
+    }
+
+    /// Snapshot containing at least the flag `key` and its prerequisites,
+    /// directly or indirectly
+    ///
+    /// Used for evaluating a single flag with prerequisites. Defaults to a
+    /// full [snapshot](Self::snapshot), stores where that is expensive
+    /// should override it. Like a full snapshot, all flags must come from
+    /// a single consistent read of the store.
+    fn snapshot_for(&self, _key: &str) -> StoreSnapshot {
+        self.snapshot()
+    }
+
+    /// All user segments, by key
+    ///
+    /// Segments are kept for exporting them, they are not evaluated yet.
+    /// Stores without segments return none.
+    fn export_segments(&self) -> HashMap<String, Arc<SegmentState>> {
+        HashMap::new()
+    }
+}
+
+/// Flags of a [Store] at one point in time, see [Store::snapshot]
+///
+/// Cheap to clone, the flags are shared with the store.
+#[derive(Clone, Default)]
+pub struct StoreSnapshot {
+    flags: Arc<HashMap<String, Arc<CompiledFlag>>>,
+    initialized: bool,
+}
+
+impl StoreSnapshot {
+    pub fn new(flags: Arc<HashMap<String, Arc<CompiledFlag>>>, initialized: bool) -> Self {
+        Self { flags, initialized }
+    }
+
+    /// All flags in the snapshot, by key
+    pub fn flags(&self) -> &HashMap<String, Arc<CompiledFlag>> {
+        &self.flags
+    }
 }
 
+impl Store for StoreSnapshot {
+    fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>> {
+        self.flags.get(name).cloned()
+    }
+
+    fn export_all(&self) -> HashMap<String, Arc<CompiledFlag>> {
+        self.flags.as_ref().clone()
+    }
+
+    fn initialized(&self) -> bool {
+        self.initialized
+    }
+
+    fn snapshot(&self) -> StoreSnapshot {
+        self.clone()
+    }
+}
+
+/// Keeps all flags and segments in memory
+///
+/// Flags are validated when they are received,
+/// problems are logged and available through [Store::diagnostics].
 pub struct MemoryStore {
-    flags: ArcSwap<HashMap<String, FeatureFlagState>>,
+    flags: ArcSwap<HashMap<String, Arc<CompiledFlag>>>,
+    segments: ArcSwap<HashMap<String, Arc<SegmentState>>>,
+    diagnostics: ArcSwap<HashMap<String, Vec<Diagnostic>>>,
     init: AtomicBool,
 }
 
@@ -29,48 +120,134 @@ impl MemoryStore {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Store flags and segments without marking the store as initialized
+    ///
+    /// Lets a client evaluate a warm start [Snapshot](crate::snapshot::Snapshot)
+    /// until its initial data arrives, which replaces the seeded data.
+    pub(crate) fn seed(&self, data: InitData) {
+        let InitData { flags, segments } = data;
+        let flags = flags
+            .into_iter()
+            .map(|(k, f)| (k, Arc::new(f.into())))
+            .collect();
+        self.replace(flags, None);
+        let segments = segments
+            .into_iter()
+            .map(|(k, s)| (k, Arc::new(s)))
+            .collect();
+        self.segments.store(Arc::new(segments));
+    }
+
+    /// Validate and store a new set of flags
+    ///
+    /// When only the `changed` flag differs from the stored flags,
+    /// only it and the flags depending on it are validated again.
+    fn replace(&self, flags: HashMap<String, Arc<CompiledFlag>>, changed: Option<&str>) {
+        let previous = self.diagnostics.load();
+        let diagnostics = match changed {
+            Some(key) => {
+                let mut diagnostics = previous.as_ref().clone();
+                update_diagnostics(&flags, &mut diagnostics, key);
+                diagnostics
+            }
+            None => validate_flags(&flags),
+        };
+        for (key, problems) in &diagnostics {
+            // only report new problems
+            if previous.get(key) == Some(problems) {
+                continue;
+            }
+            for problem in problems {
+                warn!(flag = %key, %problem, "malformed flag data");
+            }
+        }
+        self.flags.store(Arc::new(flags));
+        self.diagnostics.store(Arc::new(diagnostics));
+    }
 }
 
 impl Default for MemoryStore {
     fn default() -> Self {
-        let flags = ArcSwap::new(Arc::new(HashMap::new()));
         Self {
-            flags,
+            flags: ArcSwap::new(Arc::new(HashMap::new())),
+            segments: ArcSwap::new(Arc::new(HashMap::new())),
+            diagnostics: ArcSwap::new(Arc::new(HashMap::new())),
             init: AtomicBool::new(false),
         }
     }
 }
 
 impl Store for MemoryStore {
-    fn flag(&self, name: &str) -> Option<FeatureFlagState> {
+    fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>> {
         self.flags.load().get(name).cloned()
     }
 
-    fn export_all(&self) -> HashMap<String, FeatureFlagState> {
+    fn export_all(&self) -> HashMap<String, Arc<CompiledFlag>> {
         self.flags.load().as_ref().clone()
     }
+
+    fn initialized(&self) -> bool {
+        self.init.load(Ordering::SeqCst)
+    }
+
+    fn diagnostics(&self) -> HashMap<String, Vec<Diagnostic>> {
+        self.diagnostics.load().as_ref().clone()
+    }
+
+    fn snapshot(&self) -> StoreSnapshot {
+        // flags are stored before the init flag is set
+        let initialized = self.initialized();
+        StoreSnapshot::new(self.flags.load_full(), initialized)
+    }
+
+    fn export_segments(&self) -> HashMap<String, Arc<SegmentState>> {
+        self.segments.load().as_ref().clone()
+    }
 }
 
 impl<T: Store> Store for Arc<T> {
-    fn flag(&self, name: &str) -> Option<FeatureFlagState> {
+    fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>> {
         self.as_ref().flag(name)
     }
 
-    fn export_all(&self) -> HashMap<String, FeatureFlagState> {
+    fn export_all(&self) -> HashMap<String, Arc<CompiledFlag>> {
         self.as_ref().export_all()
     }
-}
 
-impl<S> Consumer<S> for MemoryStore {
-    type Error = Infallible;
-    type Future = Ready<Result<InitState, Self::Error>>;
+    fn initialized(&self) -> bool {
+        self.as_ref().initialized()
+    }
 
-    fn consume(&self, msg: Message) -> Self::Future {
+    fn diagnostics(&self) -> HashMap<String, Vec<Diagnostic>> {
+        self.as_ref().diagnostics()
+    }
+
+    fn snapshot(&self) -> StoreSnapshot {
+        self.as_ref().snapshot()
+    }
+
+    fn snapshot_for(&self, key: &str) -> StoreSnapshot {
+        self.as_ref().snapshot_for(key)
+    }
+
+    fn export_segments(&self) -> HashMap<String, Arc<SegmentState>> {
+        self.as_ref().export_segments()
+    }
+}
+
+impl MemoryStore {
+    /// Apply a message to the stored flags and segments
+    ///
+    /// Returns whether the data changed. Updates sent before the initial
+    /// data and updates older than the stored flag or segment are ignored.
+    pub fn apply(&self, msg: Message) -> bool {
         match msg {
             // initialize flag data
-            Message::Put(InitData { flags }) => {
-                self.flags.store(Arc::new(flags));
+            Message::Put(data) => {
+                self.seed(data);
                 self.init.store(true, Ordering::SeqCst);
+                true
             }
             // update a single flag
             Message::Patch(Update::Flag {
@@ -78,24 +255,25 @@ impl<S> Consumer<S> for MemoryStore {
                 data: Some(flag),
                 ..
             }) => {
-                if !self.init.load(Ordering::SeqCst) {
+                if !self.initialized() {
                     warn!("ignoring update sent before init");
-                    return future::ready(Ok(InitState::Pending));
+                    return false;
                 }
                 let mut updated = {
                     // Drop once cloned - don't hold guard while storing
                     let flags = self.flags.load();
                     if let Some(existing) = flags.get(&name) {
                         // check that incoming version is newer than what we have
-                        if flag.version > existing.version {
+                        if flag.version <= existing.version {
                             info!("flag already up-to-date, ignoring");
-                            return future::ready(Ok(InitState::Done));
+                            return false;
                         }
                     }
                     flags.as_ref().clone()
                 };
-                updated.insert(name, flag);
-                self.flags.store(Arc::new(updated));
+                updated.insert(name.clone(), Arc::new(flag.into()));
+                self.replace(updated, Some(&name));
+                true
             }
             // delete a flag
             Message::Delete(Update::Flag {
@@ -103,9 +281,9 @@ impl<S> Consumer<S> for MemoryStore {
                 version: Some(version),
                 ..
             }) => {
-                if !self.init.load(Ordering::SeqCst) {
+                if !self.initialized() {
                     warn!("ignoring delete sent before init");
-                    return future::ready(Ok(InitState::Pending));
+                    return false;
                 }
                 let updated = {
                     // Drop once cloned - don't hold guard while storing
@@ -120,17 +298,260 @@ impl<S> Consumer<S> for MemoryStore {
                             f
                         })
                 };
-                if let Some(updated) = updated {
-                    self.flags.store(Arc::new(updated));
+                match updated {
+                    Some(updated) => {
+                        self.replace(updated, Some(&name));
+                        true
+                    }
+                    None => false,
                 }
             }
+            // update a single segment
+            Message::Patch(Update::Segment {
+                name,
+                data: Some(segment),
+                ..
+            }) => {
+                if !self.initialized() {
+                    warn!("ignoring update sent before init");
+                    return false;
+                }
+                let version = segment.version;
+                self.update_segment(&name, version, Some(segment))
+            }
+            // delete a segment
+            Message::Delete(Update::Segment {
+                name,
+                version: Some(version),
+                ..
+            }) => {
+                if !self.initialized() {
+                    warn!("ignoring delete sent before init");
+                    return false;
+                }
+                self.update_segment(&name, version, None)
+            }
             msg => {
                 warn!(
                     ?msg,
                     "unknown update, missing some info or not yet implemented"
                 );
+                false
+            }
+        }
+    }
+
+    /// Store or delete (`None`) a segment if `version` is newer than the stored one
+    ///
+    /// Returns whether the segment changed.
+    fn update_segment(&self, name: &str, version: u64, segment: Option<SegmentState>) -> bool {
+        let mut updated = {
+            // Drop once cloned - don't hold guard while storing
+            let segments = self.segments.load();
+            match segments.get(name) {
+                Some(existing) if version <= existing.version => {
+                    info!("segment already up-to-date, ignoring");
+                    return false;
+                }
+                // nothing to delete
+                None if segment.is_none() => return false,
+                _ => segments.as_ref().clone(),
             }
         };
-        future::ready(Ok(InitState::Done))
+        match segment {
+            Some(segment) => updated.insert(name.into(), Arc::new(segment)),
+            None => updated.remove(name),
+        };
+        self.segments.store(Arc::new(updated));
+        true
+    }
+}
+
+impl<S> Consumer<S> for MemoryStore {
+    type Error = Infallible;
+    type Future = Ready<Result<InitState, Self::Error>>;
+
+    fn consume(&self, msg: Message) -> Self::Future {
+        self.apply(msg);
+        let state = if self.initialized() {
+            InitState::Done
+        } else {
+            InitState::Pending
+        };
+        future::ready(Ok(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryStore, Store};
+    use crate::{
+        consumer::Consumer,
+        evaluator::{Batch, User},
+        message::{InitData, Message, Update},
+        models::SegmentState,
+        test_utils::{FlagBuilder, NullSource},
+        validation::{Location, Problem},
+    };
+
+    async fn consume(store: &MemoryStore, msg: Message) {
+        <MemoryStore as Consumer<NullSource>>::consume(store, msg)
+            .await
+            .expect("failed to consume");
+    }
+
+    #[tokio::test]
+    async fn diagnostics() {
+        let store = MemoryStore::new();
+        let dependent = FlagBuilder::default()
+            .with_key("dependent")
+            .add_prerequisite("prereq", 1)
+            .add_target(2, "test-user")
+            .into_inner();
+        let flags = vec![(dependent.key.clone(), dependent)]
+            .into_iter()
+            .collect();
+        consume(&store, Message::Put(InitData::with_flags(flags))).await;
+
+        let diagnostics = store.diagnostics();
+        let problems: Vec<_> = diagnostics["dependent"]
+            .iter()
+            .map(|d| (d.location, d.problem.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (Location::Target(0), Problem::IndexOutOfRange(2)),
+                (
+                    Location::Prerequisite(0),
+                    Problem::MissingPrerequisite("prereq".into())
+                ),
+            ],
+            problems
+        );
+
+        // adding the prerequisite resolves the reference
+        let prereq = FlagBuilder::default().with_key("prereq").into_inner();
+        let update = Update::Flag {
+            name: "prereq".into(),
+            data: Some(prereq),
+            version: None,
+        };
+        consume(&store, Message::Patch(update)).await;
+        let diagnostics = store.diagnostics();
+        assert_eq!(1, diagnostics["dependent"].len());
+        assert!(!diagnostics.contains_key("prereq"));
+    }
+
+    #[test]
+    fn update_versions() {
+        let store = MemoryStore::new();
+        let flag = |version| {
+            let mut flag = FlagBuilder::default().with_key("flag").into_inner();
+            flag.version = version;
+            flag
+        };
+        let patch = |version| {
+            Message::Patch(Update::Flag {
+                name: "flag".into(),
+                data: Some(flag(version)),
+                version: None,
+            })
+        };
+        assert!(!store.apply(patch(1)));
+        let flags = vec![("flag".to_string(), flag(2))].into_iter().collect();
+        assert!(store.apply(Message::Put(InitData::with_flags(flags))));
+
+        assert!(!store.apply(patch(1)));
+        assert!(!store.apply(patch(2)));
+        assert_eq!(2, store.flag("flag").unwrap().version);
+        assert!(store.apply(patch(3)));
+        assert_eq!(3, store.flag("flag").unwrap().version);
+
+        let delete = |version| {
+            Message::Delete(Update::Flag {
+                name: "flag".into(),
+                data: None,
+                version: Some(version),
+            })
+        };
+        assert!(!store.apply(delete(3)));
+        assert!(store.apply(delete(4)));
+        assert!(store.flag("flag").is_none());
+    }
+
+    #[test]
+    fn segment_versions() {
+        let store = MemoryStore::new();
+        let segment = |version| SegmentState {
+            key: "segment".into(),
+            version,
+            deleted: false,
+            data: Default::default(),
+        };
+        let patch = |version| {
+            Message::Patch(Update::Segment {
+                name: "segment".into(),
+                data: Some(segment(version)),
+                version: None,
+            })
+        };
+        let delete = |version| {
+            Message::Delete(Update::Segment {
+                name: "segment".into(),
+                data: None,
+                version: Some(version),
+            })
+        };
+        let version =
+            |store: &MemoryStore| store.export_segments().get("segment").map(|s| s.version);
+        assert!(!store.apply(patch(1)));
+        let init = InitData {
+            flags: Default::default(),
+            segments: vec![("segment".to_string(), segment(2))]
+                .into_iter()
+                .collect(),
+        };
+        assert!(store.apply(Message::Put(init)));
+        assert_eq!(Some(2), version(&store));
+
+        assert!(!store.apply(patch(2)));
+        assert!(store.apply(patch(3)));
+        assert_eq!(Some(3), version(&store));
+
+        assert!(!store.apply(delete(3)));
+        assert!(store.apply(delete(4)));
+        assert_eq!(None, version(&store));
+        assert!(!store.apply(delete(5)));
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let store = MemoryStore::new();
+        let put = |prereq_on: bool| {
+            let prereq = FlagBuilder::default().with_key("prereq");
+            let prereq = if prereq_on { prereq } else { prereq.off() };
+            let dependent = FlagBuilder::default()
+                .with_key("dependent")
+                .add_prerequisite("prereq", 0)
+                .with_fallthrough_variation(1);
+            let flags = vec![prereq.into_inner(), dependent.into_inner()]
+                .into_iter()
+                .map(|f| (f.key.clone(), f))
+                .collect();
+            Message::Put(InitData::with_flags(flags))
+        };
+        consume(&store, put(true)).await;
+        let snapshot = store.snapshot();
+        assert!(snapshot.initialized());
+
+        // a new version arriving during the evaluation is not visible
+        consume(&store, put(false)).await;
+        let user = User::new("user");
+        let detail = Batch::with_snapshot(snapshot, &user)
+            .evaluate("dependent")
+            .unwrap();
+        assert_eq!(serde_json::Value::from(true), detail.value);
+        let detail = Batch::new(&store, &user).evaluate("dependent").unwrap();
+        assert_eq!(serde_json::Value::from(false), detail.value);
     }
 }
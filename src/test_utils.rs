@@ -1,16 +1,29 @@
 use crate::{
-    message::Message,
+    compiled::CompiledFlag,
+    diagnostics::DiagnosticEvent,
+    events::{Event, EventSender, SendResult},
+    message::{InitData, Message},
     models::{
-        fallthrough::Fallthrough, rollout::Rollout, target::Target,
-        weighted_variation::WeightedVariation, FeatureFlagState,
+        fallthrough::Fallthrough, prerequisite::Prerequisite, rollout::Rollout, rule::Rule,
+        target::Target, weighted_variation::WeightedVariation, FeatureFlagState,
     },
+    persistent::{AllData, DataKind, PersistentDataStore, SerializedItem},
     source::Source,
     store::Store,
 };
-use std::{collections::HashMap, convert::Infallible};
+use futures::{future, stream, StreamExt};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    vec,
+};
 
 pub struct MockStore {
-    flags: HashMap<String, FeatureFlagState>,
+    flags: HashMap<String, Arc<CompiledFlag>>,
 }
 
 impl MockStore {
@@ -20,19 +33,65 @@ impl MockStore {
         }
     }
 
-    pub fn add(&mut self, flag: FeatureFlagState) {
-        self.flags.insert(flag.key.clone(), flag);
+    pub fn add<F: Into<CompiledFlag>>(&mut self, flag: F) {
+        let flag = flag.into();
+        self.flags.insert(flag.key.clone(), Arc::new(flag));
     }
 }
 
 impl Store for MockStore {
-    fn flag(&self, name: &str) -> Option<FeatureFlagState> {
+    fn flag(&self, name: &str) -> Option<Arc<CompiledFlag>> {
         self.flags.get(name).cloned()
     }
 
-    fn export_all(&self) -> HashMap<String, FeatureFlagState> {
+    fn export_all(&self) -> HashMap<String, Arc<CompiledFlag>> {
         self.flags.clone()
     }
+
+    fn initialized(&self) -> bool {
+        true
+    }
+}
+
+/// Persistent store keeping all data in memory
+#[derive(Default)]
+pub struct MockPersistentStore {
+    data: Mutex<AllData>,
+    init: AtomicBool,
+}
+
+impl PersistentDataStore for MockPersistentStore {
+    type Error = Infallible;
+
+    fn init(&self, data: AllData) -> Result<(), Self::Error> {
+        self.data.lock().unwrap().extend(data);
+        self.init.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn get(&self, kind: DataKind, key: &str) -> Result<Option<SerializedItem>, Self::Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data.get(&kind).and_then(|items| items.get(key)).cloned())
+    }
+
+    fn get_all(&self, kind: DataKind) -> Result<HashMap<String, SerializedItem>, Self::Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data.get(&kind).cloned().unwrap_or_default())
+    }
+
+    fn upsert(&self, kind: DataKind, key: &str, item: SerializedItem) -> Result<bool, Self::Error> {
+        let mut data = self.data.lock().unwrap();
+        let items = data.entry(kind).or_default();
+        if matches!(items.get(key), Some(existing) if existing.version >= item.version) {
+            return Ok(false);
+        }
+        items.insert(key.into(), item);
+        Ok(true)
+    }
+
+    fn initialized(&self) -> Result<bool, Self::Error> {
+        Ok(self.init.load(Ordering::SeqCst))
+    }
 }
 
 pub struct NullSource;
@@ -46,6 +105,70 @@ impl Source for NullSource {
     }
 }
 
+/// Source sending init data with some flags, then waiting forever
+#[derive(Default)]
+pub struct InitSource(pub Vec<FeatureFlagState>);
+
+impl Source for InitSource {
+    type Error = Infallible;
+    type Stream = stream::Chain<
+        stream::Iter<vec::IntoIter<Result<Message, Self::Error>>>,
+        stream::Pending<Result<Message, Self::Error>>,
+    >;
+
+    fn stream(&self) -> Self::Stream {
+        let flags = self.0.iter().map(|f| (f.key.clone(), f.clone())).collect();
+        let init = Message::Put(InitData::with_flags(flags));
+        stream::iter(vec![Ok(init)]).chain(stream::pending())
+    }
+}
+
+/// Event sender recording all events as json
+#[derive(Clone)]
+pub struct MockEventSender {
+    events: Arc<Mutex<Vec<serde_json::Value>>>,
+}
+
+impl MockEventSender {
+    pub fn new() -> Self {
+        Self {
+            events: Default::default(),
+        }
+    }
+
+    /// All events sent so far
+    pub fn events(&self) -> Vec<serde_json::Value> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// All events of a kind sent so far
+    pub fn events_of_kind(&self, kind: &str) -> Vec<serde_json::Value> {
+        self.events()
+            .into_iter()
+            .filter(|e| e["kind"] == kind)
+            .collect()
+    }
+}
+
+impl EventSender for MockEventSender {
+    type Error = Infallible;
+    type Future = future::Ready<Result<SendResult, Self::Error>>;
+
+    fn send(&self, events: Vec<Event>) -> Self::Future {
+        let mut sent = self.events.lock().unwrap();
+        for event in events {
+            sent.push(serde_json::to_value(event).expect("failed to serialize event"));
+        }
+        future::ready(Ok(SendResult::default()))
+    }
+
+    fn send_diagnostic(&self, event: DiagnosticEvent) -> Self::Future {
+        let mut sent = self.events.lock().unwrap();
+        sent.push(serde_json::to_value(event).expect("failed to serialize event"));
+        future::ready(Ok(SendResult::default()))
+    }
+}
+
 pub struct FlagBuilder(FeatureFlagState);
 
 impl Default for FlagBuilder {
@@ -74,6 +197,16 @@ impl FlagBuilder {
         self
     }
 
+    pub fn track_events(mut self) -> Self {
+        self.0.track_events = true;
+        self
+    }
+
+    pub fn track_events_fallthrough(mut self) -> Self {
+        self.0.track_events_fallthrough = true;
+        self
+    }
+
     pub fn with_key<K: Into<String>>(mut self, key: K) -> Self {
         self.0.key = key.into();
         self
@@ -107,6 +240,40 @@ impl FlagBuilder {
         self
     }
 
+    pub fn with_fallthrough_experiment<I: IntoIterator<Item = (u32, u32, bool)>>(
+        mut self,
+        variations: I,
+    ) -> Self {
+        let variations = variations.into_iter().map(|(v, w, untracked)| {
+            WeightedVariation::builder()
+                .variation(v)
+                .weight(w)
+                .untracked(untracked)
+                .into()
+        });
+        let rollout = Rollout::builder()
+            .kind("experiment")
+            .variations(variations)
+            .into();
+        self.0.fallthrough = Fallthrough::builder().rollout(rollout).into();
+        self
+    }
+
+    pub fn add_rule(mut self, rule: Rule) -> Self {
+        self.0.rules.push(rule);
+        self
+    }
+
+    pub fn add_prerequisite<K: Into<String>>(mut self, key: K, variation: u32) -> Self {
+        self.0.prerequisites.push(
+            Prerequisite::builder()
+                .key(key.into())
+                .variation(variation)
+                .into(),
+        );
+        self
+    }
+
     pub fn clear_targets(mut self) -> Self {
         self.0.targets = Default::default();
         self
@@ -136,4 +303,8 @@ impl FlagBuilder {
     pub fn into_inner(self) -> FeatureFlagState {
         self.0
     }
+
+    pub fn compile(self) -> CompiledFlag {
+        CompiledFlag::new(self.0)
+    }
 }
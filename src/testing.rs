@@ -0,0 +1,163 @@
+//! Helpers for testing applications using the SDK
+//!
+//! Enabled with the `testing` feature.
+
+use crate::{
+    message::{InitData, Message},
+    models::FeatureFlagState,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Local server speaking the LaunchDarkly streaming protocol
+///
+/// Every stream starts with a `put` of the current flags, followed by the
+/// events sent through the server. Messages sent are not added to the flags.
+pub struct MockStreamServer {
+    addr: std::net::SocketAddr,
+    state: Arc<Mutex<MockStreamState>>,
+}
+
+#[derive(Default)]
+struct MockStreamState {
+    flags: HashMap<String, FeatureFlagState>,
+    /// status codes to answer the next requests with, instead of a stream
+    errors: VecDeque<u16>,
+    /// open streams
+    clients: Vec<std::sync::mpsc::Sender<StreamCommand>>,
+    /// authorization header of every request
+    requests: Vec<Option<String>>,
+}
+
+enum StreamCommand {
+    Write(String),
+    Disconnect,
+}
+
+impl MockStreamServer {
+    /// Listen on a random local port
+    pub fn start() -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(MockStreamState::default()));
+        let shared = Arc::clone(&state);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = Arc::clone(&shared);
+                std::thread::spawn(move || Self::serve(stream, state));
+            }
+        });
+        Self { addr, state }
+    }
+
+    /// URL of the stream, for [SseSource::with_url](crate::source::SseSource::with_url)
+    pub fn url(&self) -> String {
+        format!("http://{}/all", self.addr)
+    }
+
+    /// Flags sent to streams opened from now on
+    pub fn set_flags<I: IntoIterator<Item = FeatureFlagState>>(&self, flags: I) {
+        self.state.lock().unwrap().flags = flags.into_iter().map(|f| (f.key.clone(), f)).collect();
+    }
+
+    /// Send a message to all open streams
+    pub fn send(&self, msg: &Message) {
+        let data = serde_json::to_string(msg).expect("failed to serialize message");
+        self.write(format!("event: {}\ndata: {}\n\n", msg.event_type(), data));
+    }
+
+    /// Send a comment to all open streams, like the keep-alive of LaunchDarkly
+    pub fn heartbeat(&self) {
+        self.write(":\n\n".into());
+    }
+
+    /// Close all open streams
+    pub fn disconnect(&self) {
+        for client in self.state.lock().unwrap().clients.drain(..) {
+            let _ = client.send(StreamCommand::Disconnect);
+        }
+    }
+
+    /// Answer the next request with an HTTP error
+    pub fn fail_next(&self, status: u16) {
+        self.state.lock().unwrap().errors.push_back(status);
+    }
+
+    /// Authorization header of every request received so far
+    pub fn requests(&self) -> Vec<Option<String>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    fn write(&self, chunk: String) {
+        let mut state = self.state.lock().unwrap();
+        // drop streams closed by the client
+        state
+            .clients
+            .retain(|client| client.send(StreamCommand::Write(chunk.clone())).is_ok());
+    }
+
+    fn serve(stream: std::net::TcpStream, state: Arc<Mutex<MockStreamState>>) {
+        use std::io::{BufRead, Write};
+
+        let mut writer = stream.try_clone().expect("failed to clone stream");
+        let mut reader = std::io::BufReader::new(stream);
+        let mut authorization = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(n) if n > 0 => {}
+                _ => return,
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let put = {
+            let mut state = state.lock().unwrap();
+            state.requests.push(authorization);
+            if let Some(status) = state.errors.pop_front() {
+                let _ = write!(
+                    writer,
+                    "HTTP/1.1 {} Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                return;
+            }
+            state.clients.push(tx);
+            Message::Put(InitData::with_flags(state.flags.clone()))
+        };
+
+        // the body ends when the connection closes
+        let head =
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n";
+        let put = format!(
+            "event: put\ndata: {}\n\n",
+            serde_json::to_string(&put).unwrap()
+        );
+        if writer.write_all(head.as_bytes()).is_err() || writer.write_all(put.as_bytes()).is_err() {
+            return;
+        }
+        for command in rx {
+            match command {
+                StreamCommand::Write(chunk) => {
+                    if writer.write_all(chunk.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+                StreamCommand::Disconnect => break,
+            }
+        }
+        let _ = writer.shutdown(std::net::Shutdown::Both);
+    }
+}
@@ -0,0 +1,368 @@
+//! Checks for malformed flag data
+//!
+//! Every flag is validated when it is [compiled](CompiledFlag).
+//! Problems involving other flags, like missing prerequisites,
+//! are found by [validate_flags].
+
+use crate::{compiled::CompiledFlag, evaluator::Error, models::rollout::Rollout};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+/// Sum of the weights in a rollout covering all users
+const TOTAL_WEIGHT: i64 = 100_000;
+
+/// Problem found in a flag, along with where it was found
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.problem)
+    }
+}
+
+/// Part of a flag, items are identified by their index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    OffVariation,
+    Prerequisite(usize),
+    Target(usize),
+    Rule(usize),
+    Clause { rule: usize, clause: usize },
+    Fallthrough,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OffVariation => write!(f, "off variation"),
+            Self::Prerequisite(index) => write!(f, "prerequisite {}", index),
+            Self::Target(index) => write!(f, "target {}", index),
+            Self::Rule(index) => write!(f, "rule {}", index),
+            Self::Clause { rule, clause } => write!(f, "rule {} clause {}", rule, clause),
+            Self::Fallthrough => write!(f, "fallthrough"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum Problem {
+    #[error("Variation index {0} is out of range")]
+    IndexOutOfRange(i64),
+
+    #[error("Prerequisite is missing its key or variation")]
+    InvalidPrerequisite,
+
+    #[error("Prerequisite flag {0} does not exist")]
+    MissingPrerequisite(String),
+
+    #[error("Prerequisite flag {0} depends on this flag")]
+    PrerequisiteCycle(String),
+
+    #[error("Target is missing its values or variation")]
+    InvalidTarget,
+
+    #[error("Rule has neither a variation nor a rollout")]
+    InvalidRule,
+
+    #[error("Clause is missing its attribute or operator")]
+    InvalidClause,
+
+    #[error("Clause operator {0} is not supported")]
+    UnsupportedOperator(String),
+
+    #[error("Rollout has no variations or a variation without weight")]
+    InvalidRollout,
+
+    #[error("Rollout weights add up to {0} instead of 100000")]
+    RolloutWeights(i64),
+
+    #[error("Fallthrough has neither a variation nor a rollout")]
+    EmptyFallthrough,
+}
+
+impl Problem {
+    /// Error returned when evaluating a flag with this problem
+    ///
+    /// Some problems don't prevent evaluation, e.g. a missing prerequisite
+    /// is treated like a prerequisite that is not met.
+    pub fn error(&self) -> Option<Error> {
+        match self {
+            Self::IndexOutOfRange(_) => Some(Error::IndexOutOfRange),
+            Self::InvalidPrerequisite | Self::PrerequisiteCycle(_) => {
+                Some(Error::InvalidPrerequisite)
+            }
+            Self::InvalidTarget => Some(Error::InvalidTarget),
+            Self::InvalidRule | Self::InvalidClause => Some(Error::InvalidRule),
+            Self::UnsupportedOperator(_) => Some(Error::UnsupportedOperator),
+            Self::InvalidRollout => Some(Error::InvalidRollout),
+            Self::EmptyFallthrough => Some(Error::EmptyFallthrough),
+            Self::MissingPrerequisite(_) | Self::RolloutWeights(_) => None,
+        }
+    }
+}
+
+/// Collects the problems of a single flag
+pub(crate) struct Validation {
+    variations: usize,
+    pub(crate) diagnostics: Vec<Diagnostic>,
+}
+
+impl Validation {
+    pub(crate) fn new(variations: usize) -> Self {
+        Self {
+            variations,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// First problem preventing evaluation reported after the first `start` ones
+    pub(crate) fn first_error(&self, start: usize) -> Option<Error> {
+        self.diagnostics[start..]
+            .iter()
+            .find_map(|d| d.problem.error())
+    }
+
+    pub(crate) fn report(&mut self, location: Location, problem: Problem) {
+        self.diagnostics.push(Diagnostic { location, problem });
+    }
+
+    pub(crate) fn check_index(&mut self, location: Location, index: i64) {
+        if index < 0 || index as usize >= self.variations {
+            self.report(location, Problem::IndexOutOfRange(index));
+        }
+    }
+
+    pub(crate) fn check_rollout(&mut self, location: Location, rollout: &Rollout) {
+        let variations = match &rollout.variations {
+            Some(variations) if !variations.is_empty() => variations,
+            _ => return self.report(location, Problem::InvalidRollout),
+        };
+        let mut sum = 0;
+        for variation in variations {
+            match (variation.variation, variation.weight) {
+                (Some(index), Some(weight)) => {
+                    self.check_index(location, index);
+                    sum += weight;
+                }
+                _ => return self.report(location, Problem::InvalidRollout),
+            }
+        }
+        if sum != TOTAL_WEIGHT {
+            self.report(location, Problem::RolloutWeights(sum));
+        }
+    }
+}
+
+/// Problems of all flags, including references between them
+///
+/// Only contains flags with problems.
+pub fn validate_flags(
+    flags: &HashMap<String, Arc<CompiledFlag>>,
+) -> HashMap<String, Vec<Diagnostic>> {
+    let mut all = HashMap::new();
+    for (key, flag) in flags {
+        let diagnostics = validate_flag(flags, flag);
+        if !diagnostics.is_empty() {
+            all.insert(key.clone(), diagnostics);
+        }
+    }
+    all
+}
+
+/// Update the problems found by [validate_flags] after a single flag changed
+///
+/// Only the changed flag and the flags depending on it are validated again.
+pub(crate) fn update_diagnostics(
+    flags: &HashMap<String, Arc<CompiledFlag>>,
+    all: &mut HashMap<String, Vec<Diagnostic>>,
+    changed: &str,
+) {
+    for key in dependents(flags, changed) {
+        let diagnostics = match flags.get(key) {
+            Some(flag) => validate_flag(flags, flag),
+            None => Vec::new(),
+        };
+        if diagnostics.is_empty() {
+            all.remove(key);
+        } else {
+            all.insert(key.to_string(), diagnostics);
+        }
+    }
+}
+
+/// Problems of a single flag, including references to other flags
+fn validate_flag(
+    flags: &HashMap<String, Arc<CompiledFlag>>,
+    flag: &CompiledFlag,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = flag.diagnostics().to_vec();
+    for (index, prereq) in flag.prerequisites.iter().enumerate() {
+        let problem = match &prereq.key {
+            Some(prereq_key) if !flags.contains_key(prereq_key) => {
+                Problem::MissingPrerequisite(prereq_key.clone())
+            }
+            Some(prereq_key) if depends_on(flags, prereq_key, &flag.key) => {
+                Problem::PrerequisiteCycle(prereq_key.clone())
+            }
+            _ => continue,
+        };
+        diagnostics.push(Diagnostic {
+            location: Location::Prerequisite(index),
+            problem,
+        });
+    }
+    diagnostics
+}
+
+/// Whether a flag is `target` or has it as a direct or indirect prerequisite
+fn depends_on(flags: &HashMap<String, Arc<CompiledFlag>>, key: &str, target: &str) -> bool {
+    let mut visited = HashSet::new();
+    let mut pending = vec![key];
+    while let Some(key) = pending.pop() {
+        if key == target {
+            return true;
+        }
+        if !visited.insert(key) {
+            continue;
+        }
+        if let Some(flag) = flags.get(key) {
+            pending.extend(flag.prerequisites.iter().filter_map(|p| p.key.as_deref()));
+        }
+    }
+    false
+}
+
+/// Keys of `key` and all flags having it as a direct or indirect prerequisite
+fn dependents<'a>(flags: &'a HashMap<String, Arc<CompiledFlag>>, key: &'a str) -> HashSet<&'a str> {
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (dependent, flag) in flags {
+        for prereq in flag.prerequisites.iter().filter_map(|p| p.key.as_deref()) {
+            dependents.entry(prereq).or_default().push(dependent);
+        }
+    }
+    let mut found = HashSet::new();
+    let mut pending = vec![key];
+    while let Some(key) = pending.pop() {
+        if found.insert(key) {
+            pending.extend(dependents.get(key).into_iter().flatten());
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update_diagnostics, validate_flags, Diagnostic, Location, Problem};
+    use crate::{compiled::CompiledFlag, evaluator::Error, test_utils::FlagBuilder};
+    use std::sync::Arc;
+
+    #[test]
+    fn rollout_weights() {
+        let flag = FlagBuilder::default()
+            .with_fallthrough_rollout(vec![(0, 30000), (1, 60000)])
+            .compile();
+        assert_eq!(
+            &[Diagnostic {
+                location: Location::Fallthrough,
+                problem: Problem::RolloutWeights(90000),
+            }],
+            flag.diagnostics()
+        );
+        // users outside the buckets fail at evaluation time only
+        assert_eq!(None, flag.fallthrough_error());
+    }
+
+    #[test]
+    fn missing_prerequisite() {
+        let flags = vec![
+            FlagBuilder::default()
+                .with_key("dependent")
+                .add_prerequisite("existing", 1)
+                .add_prerequisite("missing", 1)
+                .compile(),
+            FlagBuilder::default().with_key("existing").compile(),
+        ];
+        let flags = flags
+            .into_iter()
+            .map(|f| (f.key.clone(), Arc::new(f)))
+            .collect();
+        let all = validate_flags(&flags);
+        assert_eq!(1, all.len());
+        assert_eq!(
+            vec![Diagnostic {
+                location: Location::Prerequisite(1),
+                problem: Problem::MissingPrerequisite("missing".into()),
+            }],
+            all["dependent"]
+        );
+    }
+
+    #[test]
+    fn prerequisite_cycle() {
+        let flag = |key: &str, prereq: &str| {
+            let flag = FlagBuilder::default()
+                .with_key(key)
+                .add_prerequisite(prereq, 0)
+                .compile();
+            (key.to_string(), Arc::new(flag))
+        };
+        let mut flags = vec![flag("a", "b"), flag("b", "a"), flag("dependent", "a")]
+            .into_iter()
+            .collect();
+        let mut all = validate_flags(&flags);
+        assert_eq!(2, all.len());
+        assert_eq!(
+            vec![Diagnostic {
+                location: Location::Prerequisite(0),
+                problem: Problem::PrerequisiteCycle("b".into()),
+            }],
+            all["a"]
+        );
+        assert_eq!(
+            Some(Error::InvalidPrerequisite),
+            all["b"][0].problem.error()
+        );
+
+        // breaking the cycle updates the flags depending on the changed flag
+        let (key, b) = flag("b", "c");
+        flags.insert(key, b);
+        update_diagnostics(&flags, &mut all, "b");
+        assert_eq!(1, all.len());
+        assert_eq!(
+            Problem::MissingPrerequisite("c".into()),
+            all["b"][0].problem
+        );
+        assert_eq!(validate_flags(&flags), all);
+
+        flags.remove("b");
+        update_diagnostics(&flags, &mut all, "b");
+        assert_eq!(validate_flags(&flags), all);
+        assert_eq!(
+            Problem::MissingPrerequisite("b".into()),
+            all["a"][0].problem
+        );
+    }
+
+    #[test]
+    fn item_errors() {
+        let flag = FlagBuilder::default()
+            .add_target(5, "a")
+            .with_fallthrough_rollout(vec![])
+            .into_inner();
+        let flag = CompiledFlag::new(flag);
+        assert_eq!(2, flag.diagnostics().len());
+        assert_eq!(
+            "target 0: Variation index 5 is out of range",
+            flag.diagnostics()[0].to_string()
+        );
+        assert_eq!(Some(Err(Error::IndexOutOfRange)), flag.target("a"));
+        assert_eq!(Some(&Error::InvalidRollout), flag.fallthrough_error());
+    }
+}